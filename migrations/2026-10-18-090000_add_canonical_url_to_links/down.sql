@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX links_canonical_url;
+ALTER TABLE links DROP COLUMN canonical_url;
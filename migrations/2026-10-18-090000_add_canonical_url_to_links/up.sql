@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE links ADD COLUMN canonical_url VARCHAR NOT NULL DEFAULT '';
+CREATE INDEX links_canonical_url ON links (canonical_url);
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE links
+ADD COLUMN disabled_at TIMESTAMP,
+ADD COLUMN disabled_reason VARCHAR;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX links_deleted_at;
+ALTER TABLE links DROP COLUMN deleted_at;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE links ADD COLUMN deleted_at TIMESTAMP;
+CREATE INDEX links_deleted_at ON links (deleted_at);
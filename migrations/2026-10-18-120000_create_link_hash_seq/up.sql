@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE SEQUENCE link_hash_seq START 1;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX hash_unique;
+CREATE UNIQUE INDEX hash_unique ON links (hash);
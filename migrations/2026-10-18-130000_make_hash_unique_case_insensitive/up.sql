@@ -0,0 +1,15 @@
+-- Your SQL goes here
+-- Hashes that only differ by case can't both be kept, later ones get their id appended
+UPDATE links SET hash = lower(hash) || '-' || id
+WHERE id IN (
+  SELECT id FROM (
+    SELECT id, row_number() OVER (PARTITION BY lower(hash) ORDER BY id) AS position
+    FROM links
+  ) hashes
+  WHERE position > 1
+);
+
+UPDATE links SET hash = lower(hash) WHERE hash <> lower(hash);
+
+DROP INDEX hash_unique;
+CREATE UNIQUE INDEX hash_unique ON links (lower(hash));
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX hash_unique;
+CREATE UNIQUE INDEX hash_unique ON links (lower(hash));
+
+ALTER TABLE links DROP COLUMN domain_id;
+DROP TABLE domains;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+CREATE TABLE domains (
+  id SERIAL PRIMARY KEY,
+  host VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+CREATE UNIQUE INDEX domains_host_unique ON domains (lower(host));
+
+ALTER TABLE links ADD COLUMN domain_id INTEGER REFERENCES domains (id);
+
+-- Links without a domain are on the primary domain, hashes only have to be unique per domain
+DROP INDEX hash_unique;
+CREATE UNIQUE INDEX hash_unique ON links (COALESCE(domain_id, 0), lower(hash));
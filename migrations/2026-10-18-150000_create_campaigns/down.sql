@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE links DROP COLUMN campaign_id;
+DROP TABLE campaigns;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE campaigns (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR(255) NOT NULL,
+  description VARCHAR,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE links ADD COLUMN campaign_id INTEGER REFERENCES campaigns (id) ON DELETE SET NULL;
+CREATE INDEX links_campaign_id ON links (campaign_id);
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE tags (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR(50) NOT NULL UNIQUE
+);
+
+CREATE TABLE link_tags (
+  link_id INTEGER NOT NULL REFERENCES links (id) ON DELETE CASCADE,
+  tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+  PRIMARY KEY (link_id, tag_id)
+);
+CREATE INDEX link_tags_tag_id ON link_tags (tag_id);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE links
+DROP COLUMN description,
+DROP COLUMN image_url,
+DROP COLUMN favicon_url,
+DROP COLUMN metadata_fetched_at;
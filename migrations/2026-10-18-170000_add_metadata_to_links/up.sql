@@ -0,0 +1,6 @@
+-- Your SQL goes here
+ALTER TABLE links
+ADD COLUMN description VARCHAR(1000),
+ADD COLUMN image_url VARCHAR,
+ADD COLUMN favicon_url VARCHAR,
+ADD COLUMN metadata_fetched_at TIMESTAMP;
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE links ADD COLUMN interstitial BOOLEAN NOT NULL DEFAULT FALSE;
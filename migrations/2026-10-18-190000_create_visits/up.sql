@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE visits (
+  id BIGSERIAL PRIMARY KEY,
+  link_id INTEGER NOT NULL REFERENCES links (id) ON DELETE CASCADE,
+  channel VARCHAR(20) NOT NULL DEFAULT 'direct',
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+CREATE INDEX visits_link_id_created_at ON visits (link_id, created_at);
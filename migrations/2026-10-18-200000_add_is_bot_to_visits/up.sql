@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE visits ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT FALSE;
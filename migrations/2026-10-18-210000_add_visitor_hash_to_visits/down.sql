@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE visitor_salts;
+ALTER TABLE visits DROP COLUMN visitor_hash;
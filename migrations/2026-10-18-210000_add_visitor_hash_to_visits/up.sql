@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE visits ADD COLUMN visitor_hash VARCHAR(64);
+
+CREATE TABLE visitor_salts (
+  day DATE PRIMARY KEY,
+  salt VARCHAR(64) NOT NULL
+);
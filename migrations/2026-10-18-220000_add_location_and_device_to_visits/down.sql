@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE visits
+  DROP COLUMN country,
+  DROP COLUMN region,
+  DROP COLUMN device,
+  DROP COLUMN os,
+  DROP COLUMN browser;
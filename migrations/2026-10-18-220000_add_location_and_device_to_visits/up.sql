@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE visits
+  ADD COLUMN country VARCHAR(2),
+  ADD COLUMN region VARCHAR(255),
+  ADD COLUMN device VARCHAR(20),
+  ADD COLUMN os VARCHAR(50),
+  ADD COLUMN browser VARCHAR(50);
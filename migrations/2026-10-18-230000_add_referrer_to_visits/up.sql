@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE visits ADD COLUMN referrer VARCHAR(255);
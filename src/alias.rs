@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use rocket::fairing::AdHoc;
+
+const DEFAULT_MIN_LENGTH: usize = 3;
+const DEFAULT_MAX_LENGTH: usize = 50;
+
+// Rules for custom hashes.
+//
+// Configured through the environment:
+//   ALIAS_MIN_LENGTH  defaults to 3
+//   ALIAS_MAX_LENGTH  defaults to 50
+//   RESERVED_ALIASES  comma separated, on top of every mounted route prefix
+pub struct AliasRules {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub reserved: HashSet<String>,
+}
+
+impl AliasRules {
+    pub fn from_env() -> Self {
+        let reserved = std::env::var("RESERVED_ALIASES")
+            .map(|aliases| {
+                aliases
+                    .split(',')
+                    .map(|alias| alias.trim().to_lowercase())
+                    .filter(|alias| !alias.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        AliasRules {
+            min_length: env_usize("ALIAS_MIN_LENGTH").unwrap_or(DEFAULT_MIN_LENGTH),
+            max_length: env_usize("ALIAS_MAX_LENGTH").unwrap_or(DEFAULT_MAX_LENGTH),
+            reserved,
+        }
+    }
+
+    // Reserves the first static segment of every mounted route, e.g. `api` and `public`
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_ignite("Alias Rules", |rocket| async {
+            let mut rules = AliasRules::from_env();
+
+            for route in rocket.routes() {
+                let prefix = route
+                    .uri
+                    .path()
+                    .trim_start_matches('/')
+                    .split('/')
+                    .next()
+                    .unwrap_or("")
+                    .to_lowercase();
+
+                if !prefix.is_empty() && !prefix.starts_with('<') {
+                    rules.reserved.insert(prefix);
+                }
+            }
+
+            rocket.manage(rules)
+        })
+    }
+
+    // Hashes are case-insensitive, so aliases are stored lowercased
+    pub fn normalize(&self, alias: &str) -> Result<String, String> {
+        let alias = alias.trim().to_lowercase();
+        let mut errors = vec![];
+        let length = alias.chars().count();
+
+        if length < self.min_length || length > self.max_length {
+            errors.push(format!(
+                "Custom hash must be between {} and {} characters",
+                self.min_length, self.max_length
+            ));
+        }
+
+        if !alias.chars().all(is_hash_char) {
+            errors.push(
+                "Custom hash can only contain letters, numbers, dashes and underscores"
+                    .to_string(),
+            );
+        }
+
+        if self.reserved.contains(&alias) {
+            errors.push(format!("Custom hash '{}' is reserved", alias));
+        }
+
+        if errors.is_empty() {
+            Ok(alias)
+        } else {
+            Err(errors.join(", "))
+        }
+    }
+}
+
+// What any hash, custom or generated, can be made of once lowercased
+pub fn is_hash_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_'
+}
+
+fn env_usize(name: &str) -> Option<usize> {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+}
@@ -1,14 +1,90 @@
-use rocket::{serde::json::Json, request::{FromRequest, Outcome}, Request, http::Status};
+use rocket::{serde::json::Json, request::{FromRequest, Outcome}, response::Redirect, Request, http::Status};
+use rocket_dyn_templates::Template;
 use serde::{Deserialize, Serialize};
 
+use crate::campaign::Campaign;
 use crate::link::Link;
+use crate::tag;
+use crate::visit::{self, ChannelCounts};
+use crate::webhook::Webhook;
+use crate::DbConn;
+use diesel::QueryResult;
 
 #[derive(Serialize, Deserialize)]
 pub struct LinkRequest {
     pub url: String,
     pub visible: bool,
     pub custom_hash: Option<String>,
-    pub title: Option<String>
+    pub title: Option<String>,
+    pub domain: Option<String>,
+    pub campaign_id: Option<i32>,
+    pub tags: Option<Vec<String>>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub fetch_metadata: Option<bool>,
+    pub interstitial: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DomainRequest {
+    pub host: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LinkUpdateRequest {
+    pub url: Option<String>,
+    pub visible: Option<bool>,
+    pub title: Option<String>,
+    // Missing leaves the campaign alone, null takes the link out of it
+    #[serde(default, deserialize_with = "present")]
+    pub campaign_id: Option<Option<i32>>,
+    pub tags: Option<Vec<String>>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub interstitial: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CampaignRequest {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WebhookRequest {
+    pub url: String,
+    pub events: Option<Vec<String>>,
+    pub secret: Option<String>,
+    pub click_threshold: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WebhookUpdateRequest {
+    pub url: Option<String>,
+    pub events: Option<Vec<String>>,
+    pub secret: Option<String>,
+    // Missing leaves the threshold alone, null removes it
+    #[serde(default, deserialize_with = "present")]
+    pub click_threshold: Option<Option<i32>>,
+}
+
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: rocket::serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CampaignUpdateRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableRequest {
+    pub reason: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -20,11 +96,42 @@ pub struct Error {
 pub struct LinkResponse {
     pub id: i32,
     pub short_url: String,
+    pub qr_url: String,
     url: String,
+    pub canonical_url: String,
     created_at: chrono::NaiveDateTime,
     visible: bool,
     visitors: i32,
     title: Option<String>,
+    pub disabled_at: Option<chrono::NaiveDateTime>,
+    pub disabled_reason: Option<String>,
+    pub campaign_id: Option<i32>,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub favicon_url: Option<String>,
+    pub interstitial: bool,
+    pub channels: ChannelCounts,
+    pub human_visits: i64,
+    pub bot_visits: i64,
+    pub unique_visitors: i64,
+}
+
+impl LinkResponse {
+    // Fills in everything about the link that isn't stored on the row itself
+    pub async fn build(link: Link, conn: &DbConn) -> QueryResult<Self> {
+        let tags = tag::for_link(link.id, conn).await?;
+        let stats = visit::stats(link.id, conn).await?;
+
+        Ok(LinkResponse {
+            tags,
+            channels: stats.channels,
+            human_visits: stats.visitors.human,
+            bot_visits: stats.visitors.bot,
+            unique_visitors: stats.unique_visitors,
+            ..LinkResponse::from(link)
+        })
+    }
 }
 
 impl From<Link> for LinkResponse {
@@ -32,11 +139,75 @@ impl From<Link> for LinkResponse {
         LinkResponse {
             id: link.id,
             short_url: link.redirect_url(),
+            qr_url: link.qr_url(),
             url: link.url,
+            canonical_url: link.canonical_url,
             visible: link.visible,
             visitors: link.visitors,
             created_at: link.created_at,
             title: link.title,
+            disabled_at: link.disabled_at,
+            disabled_reason: link.disabled_reason,
+            campaign_id: link.campaign_id,
+            tags: vec![],
+            description: link.description,
+            image_url: link.image_url,
+            favicon_url: link.favicon_url,
+            interstitial: link.interstitial,
+            channels: ChannelCounts::default(),
+            human_visits: 0,
+            bot_visits: 0,
+            unique_visitors: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CampaignResponse {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub links: i64,
+    pub visitors: i64,
+    pub unique_visitors: i64,
+}
+
+impl CampaignResponse {
+    pub fn new(campaign: Campaign, (links, visitors, unique_visitors): (i64, i64, i64)) -> Self {
+        CampaignResponse {
+            id: campaign.id,
+            name: campaign.name,
+            description: campaign.description,
+            created_at: campaign.created_at,
+            links,
+            visitors,
+            unique_visitors,
+        }
+    }
+}
+
+// The secret is only sent back when it's generated or changed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebhookResponse {
+    pub id: i32,
+    pub url: String,
+    pub events: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub click_threshold: Option<i32>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl WebhookResponse {
+    pub fn new(webhook: Webhook, with_secret: bool) -> Self {
+        WebhookResponse {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+            secret: Some(webhook.secret).filter(|_| with_secret),
+            click_threshold: webhook.click_threshold,
+            created_at: webhook.created_at,
         }
     }
 }
@@ -59,6 +230,8 @@ pub enum APIResult {
     Unauthorized(Json<Error>),
     #[response(status = 500)]
     InternalServerError(Json<Error>),
+    #[response(status = 409)]
+    Conflict(Json<Error>),
     #[response(status = 422)]
     UnprocessableEntity(Json<Error>),
     #[response(status = 201)]
@@ -83,20 +256,32 @@ impl APIResult {
     pub fn internal_server_error(error: String) -> Self {
         APIResult::InternalServerError(Json(Error { error }))
     }
+    pub fn conflict(error: String) -> Self {
+        APIResult::Conflict(Json(Error { error }))
+    }
     pub fn unprocessable_entity(error: String) -> Self {
         APIResult::UnprocessableEntity(Json(Error { error }))
     }
-    pub fn created(link: Link) -> Self {
-        APIResult::Created(Json(LinkResponse::from(link)))
+    pub fn created(link: LinkResponse) -> Self {
+        APIResult::Created(Json(link))
     }
-    pub fn ok(link: Link) -> Self {
-        APIResult::Ok(Json(LinkResponse::from(link)))
+    pub fn ok(link: LinkResponse) -> Self {
+        APIResult::Ok(Json(link))
     }
     pub fn no_content() -> Self {
         APIResult::NoContent(Json(Error { error: "No content".to_string() }))
     }
 }
 
+#[derive(Responder)]
+pub enum RedirectResult {
+    Redirect(Redirect),
+    Preview(Template),
+    Interstitial(Template),
+    #[response(status = 410)]
+    Disabled(Template),
+}
+
 #[derive(Debug)]
 pub enum APIKeyError {
     Missing,
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{link::Link, DbConn, DbPool};
+
+const DEFAULT_CAPACITY: usize = 10_000;
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+// Hashes are case-insensitive, so keys use the lowercased hash
+type Key = (Option<i32>, String);
+
+struct Entry {
+    // None when no link has the hash
+    link: Option<Link>,
+    expires_at: Instant,
+}
+
+struct Entries {
+    links: LruCache<Key, Entry>,
+    // Only keys with a lookup in flight, invalidate bumps the generation so the lookup
+    // doesn't cache what it read before the change
+    generations: HashMap<Key, Generation>,
+}
+
+#[derive(Default)]
+struct Generation {
+    value: u64,
+    lookups: usize,
+}
+
+// A lookup that missed the cache, whose result is only cached if the key wasn't
+// invalidated in the meantime
+pub struct Miss<'a> {
+    cache: &'a LinkCache,
+    key: Key,
+    generation: u64,
+}
+
+impl Miss<'_> {
+    pub fn put(self, link: Option<Link>) {
+        self.cache.put(&self.key, self.generation, link);
+    }
+}
+
+impl Drop for Miss<'_> {
+    fn drop(&mut self) {
+        self.cache.release(&self.key);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+// Caches redirect lookups by domain and hash, so most redirects skip the database.
+// Anything that changes whether or where a hash redirects must invalidate it.
+#[derive(Clone)]
+pub struct LinkCache {
+    entries: Option<Arc<Mutex<Entries>>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl LinkCache {
+    pub fn new(capacity: usize, ttl: Duration, negative_ttl: Duration) -> Self {
+        LinkCache {
+            entries: NonZeroUsize::new(capacity).map(|capacity| {
+                Arc::new(Mutex::new(Entries {
+                    links: LruCache::new(capacity),
+                    generations: HashMap::new(),
+                }))
+            }),
+            ttl,
+            negative_ttl,
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // LINK_CACHE_CAPACITY of 0 turns the cache off, both TTLs are in seconds
+    pub fn from_env() -> Self {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+
+        LinkCache::new(
+            var("LINK_CACHE_CAPACITY").map_or(DEFAULT_CAPACITY, |capacity| capacity as usize),
+            var("LINK_CACHE_TTL").map_or(DEFAULT_TTL, Duration::from_secs),
+            var("LINK_CACHE_NEGATIVE_TTL").map_or(DEFAULT_NEGATIVE_TTL, Duration::from_secs),
+        )
+    }
+
+    pub async fn find_by_hash(
+        &self,
+        hash: String,
+        domain_id: Option<i32>,
+        pool: &DbPool,
+    ) -> Result<Option<Link>, String> {
+        if let Some(link) = self.get(&(domain_id, hash.to_lowercase())) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(link);
+        }
+
+        let miss = self.miss(&hash, domain_id);
+        let conn = DbConn::from_pool(pool)
+            .await
+            .ok_or_else(|| "Database connection unavailable".to_string())?;
+        let link = Link::find_by_hash(hash, domain_id, &conn)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        miss.put(link.clone());
+
+        Ok(link)
+    }
+
+    pub fn miss(&self, hash: &str, domain_id: Option<i32>) -> Miss<'_> {
+        let key = (domain_id, hash.to_lowercase());
+        let generation = match &self.entries {
+            Some(entries) => {
+                let mut entries = entries.lock();
+                let generation = entries.generations.entry(key.clone()).or_default();
+                generation.lookups += 1;
+                generation.value
+            }
+            None => 0,
+        };
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        Miss {
+            cache: self,
+            key,
+            generation,
+        }
+    }
+
+    fn get(&self, key: &Key) -> Option<Option<Link>> {
+        let mut entries = self.entries.as_ref()?.lock();
+
+        match entries.links.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.link.clone()),
+            Some(_) => {
+                entries.links.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, key: &Key, generation: u64, link: Option<Link>) {
+        let entries = match &self.entries {
+            Some(entries) => entries,
+            None => return,
+        };
+        let ttl = if link.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+
+        let mut entries = entries.lock();
+
+        if entries.generations.get(key).map(|current| current.value) != Some(generation) {
+            return;
+        }
+
+        entries.links.put(
+            key.clone(),
+            Entry {
+                link,
+                expires_at: Instant::now() + ttl,
+            },
+        );
+    }
+
+    fn release(&self, key: &Key) {
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock();
+
+            if let Some(generation) = entries.generations.get_mut(key) {
+                generation.lookups -= 1;
+
+                if generation.lookups == 0 {
+                    entries.generations.remove(key);
+                }
+            }
+        }
+    }
+
+    pub fn invalidate(&self, link: &Link) {
+        if let Some(entries) = &self.entries {
+            let key = (link.domain_id, link.hash.to_lowercase());
+            let mut entries = entries.lock();
+
+            entries.links.pop(&key);
+
+            if let Some(generation) = entries.generations.get_mut(&key) {
+                generation.value += 1;
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (entries, capacity) = match &self.entries {
+            Some(entries) => {
+                let entries = entries.lock();
+                (entries.links.len(), entries.links.cap().get())
+            }
+            None => (0, 0),
+        };
+
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries,
+            capacity,
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use diesel::{
+    self,
+    pg::PgConnection,
+    prelude::*,
+    sql_types::{BigInt, Integer, Nullable, Text, Timestamp},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::DbConn;
+
+use self::schema::campaigns;
+
+#[derive(Queryable, Identifiable, AsChangeset, Serialize, Deserialize, Clone, Debug)]
+#[table_name = "campaigns"]
+pub struct Campaign {
+    pub id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl Campaign {
+    pub async fn find(id: i32, conn: &DbConn) -> Result<Campaign, String> {
+        conn.run(move |c| {
+            campaigns::table
+                .find(id)
+                .get_result::<Self>(c)
+                .map_err(|_| "Campaign not found".to_string())
+        })
+        .await
+    }
+
+    pub async fn insert(
+        name: String,
+        description: Option<String>,
+        conn: &DbConn,
+    ) -> Result<Campaign, String> {
+        let errors = validate(&name, &description);
+
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+
+        conn.run(move |c| {
+            diesel::insert_into(campaigns::table)
+                .values((
+                    campaigns::name.eq(name),
+                    campaigns::description.eq(description),
+                ))
+                .get_result::<Self>(c)
+                .map_err(|e| e.to_string())
+        })
+        .await
+    }
+
+    pub async fn update(
+        mut self,
+        name: Option<String>,
+        description: Option<String>,
+        conn: &DbConn,
+    ) -> Result<Campaign, String> {
+        if let Some(name) = name {
+            self.name = name;
+        }
+
+        if description.is_some() {
+            self.description = description;
+        }
+
+        let errors = validate(&self.name, &self.description);
+
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+
+        conn.run(move |c| self.save_changes(c).map_err(|e| e.to_string()))
+            .await
+    }
+
+    // Links are kept, they just stop belonging to a campaign
+    pub async fn delete(self, conn: &DbConn) -> bool {
+        conn.run(move |c| diesel::delete(&self).execute(c).is_ok())
+            .await
+    }
+
+    pub async fn delete_all(conn: &DbConn) -> QueryResult<usize> {
+        conn.run(|c| diesel::delete(campaigns::table).execute(c))
+            .await
+    }
+
+    pub async fn all_with_totals(conn: &DbConn) -> QueryResult<Vec<(Campaign, Totals)>> {
+        conn.run(|c| load_with_totals(None, c)).await
+    }
+
+    pub async fn totals(&self, conn: &DbConn) -> QueryResult<Totals> {
+        let id = self.id;
+
+        conn.run(move |c| load_with_totals(Some(id), c))
+            .await?
+            .pop()
+            .map(|(_, totals)| totals)
+            .ok_or(diesel::result::Error::NotFound)
+    }
+}
+
+// Number of links, their combined visitors and unique visitors, deleted links don't count
+pub type Totals = (i64, i64, i64);
+
+#[derive(QueryableByName)]
+struct CampaignWithTotals {
+    #[sql_type = "Integer"]
+    id: i32,
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Nullable<Text>"]
+    description: Option<String>,
+    #[sql_type = "Timestamp"]
+    created_at: chrono::NaiveDateTime,
+    #[sql_type = "BigInt"]
+    links: i64,
+    #[sql_type = "BigInt"]
+    visitors: i64,
+    #[sql_type = "BigInt"]
+    unique_visitors: i64,
+}
+
+// Totals for every campaign, or just `id`, grouped in one query rather than per campaign.
+// Unique visitors are counted per day, like visit::unique_visitors.
+fn load_with_totals(id: Option<i32>, c: &PgConnection) -> QueryResult<Vec<(Campaign, Totals)>> {
+    let rows = diesel::sql_query(
+        "SELECT campaigns.id, campaigns.name, campaigns.description, campaigns.created_at, \
+         COALESCE(link_totals.links, 0) AS links, \
+         COALESCE(link_totals.visitors, 0) AS visitors, \
+         COALESCE(unique_totals.unique_visitors, 0) AS unique_visitors \
+         FROM campaigns \
+         LEFT JOIN ( \
+           SELECT campaign_id, COUNT(*) AS links, SUM(visitors)::BIGINT AS visitors \
+           FROM links \
+           WHERE campaign_id IS NOT NULL AND deleted_at IS NULL \
+           GROUP BY campaign_id \
+         ) link_totals ON link_totals.campaign_id = campaigns.id \
+         LEFT JOIN ( \
+           SELECT links.campaign_id, \
+           COUNT(DISTINCT (visits.created_at::date, visits.visitor_hash)) AS unique_visitors \
+           FROM visits \
+           INNER JOIN links ON links.id = visits.link_id \
+           WHERE links.campaign_id IS NOT NULL AND links.deleted_at IS NULL \
+           AND visits.visitor_hash IS NOT NULL AND NOT visits.is_bot \
+           GROUP BY links.campaign_id \
+         ) unique_totals ON unique_totals.campaign_id = campaigns.id \
+         WHERE $1::INTEGER IS NULL OR campaigns.id = $1 \
+         ORDER BY campaigns.created_at DESC",
+    )
+    .bind::<Nullable<Integer>, _>(id)
+    .load::<CampaignWithTotals>(c)?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let campaign = Campaign {
+                id: row.id,
+                name: row.name,
+                description: row.description,
+                created_at: row.created_at,
+            };
+
+            (campaign, (row.links, row.visitors, row.unique_visitors))
+        })
+        .collect())
+}
+
+fn validate(name: &str, description: &Option<String>) -> Vec<String> {
+    let mut errors = vec![];
+
+    if name.trim().is_empty() {
+        errors.push("Name cannot be empty".to_string());
+    } else if name.len() > 255 {
+        errors.push("Name cannot be over 255 characters".to_string());
+    }
+
+    if let Some(description) = description {
+        if description.len() > 1000 {
+            errors.push("Description cannot be over 1000 characters".to_string());
+        }
+    }
+
+    errors
+}
+
+pub mod schema {
+    table! {
+        campaigns (id) {
+            id -> Int4,
+            name -> Varchar,
+            description -> Nullable<Varchar>,
+            created_at -> Timestamp,
+        }
+    }
+}
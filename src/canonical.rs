@@ -0,0 +1,105 @@
+use rocket::fairing::AdHoc;
+use url::{Host, Url};
+
+use crate::{link::Link, DbConn};
+
+const DEFAULT_TRACKING_PARAMS: &[&str] = &[
+    "utm_*", "fbclid", "gclid", "dclid", "msclkid", "yclid", "mc_cid", "mc_eid", "_ga", "_hsenc",
+    "_hsmi",
+];
+
+// Produces the form of a URL used to tell whether two links point at the same place.
+// The original URL is still what visitors get redirected to.
+pub fn canonicalize(url: &str) -> Option<String> {
+    // Url::parse already lowercases the scheme
+    let mut parsed = Url::parse(url.trim()).ok()?;
+
+    normalize_host(&mut parsed);
+    strip_default_port(&mut parsed);
+    normalize_query(&mut parsed, &tracking_params());
+
+    Some(parsed.to_string().trim_end_matches('/').to_string())
+}
+
+// Links from before canonical_url existed are left blank by the migration, since only this
+// code knows how to canonicalize them. They're filled in once the server is up.
+pub fn backfill_fairing() -> AdHoc {
+    AdHoc::on_liftoff("Backfill Canonical URLs", |rocket| {
+        Box::pin(async move {
+            let conn = match DbConn::get_one(rocket).await {
+                Some(conn) => conn,
+                None => return,
+            };
+
+            if let Err(e) = Link::backfill_canonical_urls(&conn).await {
+                log::error!("Failed to backfill canonical URLs: {}", e);
+            }
+        })
+    })
+}
+
+// Comma separated list, e.g. TRACKING_PARAMS="utm_*,fbclid,ref"
+// A trailing `*` matches any parameter with that prefix
+pub fn tracking_params() -> Vec<String> {
+    match std::env::var("TRACKING_PARAMS") {
+        Ok(params) => params
+            .split(',')
+            .map(|param| param.trim().to_lowercase())
+            .filter(|param| !param.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_TRACKING_PARAMS
+            .iter()
+            .map(|param| param.to_string())
+            .collect(),
+    }
+}
+
+fn normalize_host(url: &mut Url) {
+    let host = match url.host_str() {
+        Some(host) => host.to_lowercase(),
+        None => return,
+    };
+
+    // Host::parse applies IDNA, so unicode domains end up as punycode
+    if let Ok(host) = Host::parse(&host) {
+        let _ = url.set_host(Some(&host.to_string()));
+    }
+}
+
+fn strip_default_port(url: &mut Url) {
+    let default_port = match url.scheme() {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        _ => None,
+    };
+
+    if url.port().is_some() && url.port() == default_port {
+        let _ = url.set_port(None);
+    }
+}
+
+fn normalize_query(url: &mut Url, tracking_params: &[String]) {
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking_param(key, tracking_params))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+
+    if pairs.is_empty() {
+        url.set_query(None);
+        return;
+    }
+
+    pairs.sort();
+    url.query_pairs_mut().clear().extend_pairs(pairs);
+}
+
+fn is_tracking_param(key: &str, tracking_params: &[String]) -> bool {
+    let key = key.to_lowercase();
+
+    tracking_params.iter().any(|param| match param.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == *param,
+    })
+}
@@ -0,0 +1,438 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{cache::LinkCache, link::Link, DbConn, DbPool};
+
+const RECHECK_BATCH_SIZE: i64 = 100;
+// The most URLs Safe Browsing takes in one threatMatches:find request
+const MAX_THREAT_ENTRIES: usize = 500;
+const DEFAULT_RECHECK_INTERVAL: u64 = 60 * 60;
+const SAFE_BROWSING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[rocket::async_trait]
+pub trait UrlChecker: Send + Sync {
+    // Returns why the URL was flagged, or None when it looks safe
+    async fn check(&self, url: &Url) -> Option<String>;
+
+    // The same for many URLs at once, in order. Checkers backed by an API override this
+    // to look them up in as few requests as they can.
+    async fn check_all(&self, urls: &[Url]) -> Vec<Option<String>> {
+        let mut reasons = vec![];
+
+        for url in urls {
+            reasons.push(self.check(url).await);
+        }
+
+        reasons
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct UrlCheckers(Vec<Arc<dyn UrlChecker>>);
+
+impl UrlCheckers {
+    // BLOCKLIST_PATH enables the file-backed blocklist and
+    // SAFE_BROWSING_API_KEY enables the Safe Browsing lookup
+    pub fn from_env() -> Self {
+        let mut checkers = UrlCheckers::default();
+
+        if let Ok(path) = std::env::var("BLOCKLIST_PATH") {
+            let blocklist = Arc::new(DomainBlocklist::new(PathBuf::from(path)));
+            let interval = std::env::var("BLOCKLIST_RELOAD_INTERVAL")
+                .ok()
+                .and_then(|seconds| seconds.parse::<u64>().ok())
+                .map(Duration::from_secs);
+
+            blocklist.clone().watch(interval);
+            checkers.push(blocklist);
+        }
+
+        if let Ok(api_key) = std::env::var("SAFE_BROWSING_API_KEY") {
+            let endpoint = std::env::var("SAFE_BROWSING_URL")
+                .unwrap_or_else(|_| "https://safebrowsing.googleapis.com".to_string());
+
+            checkers.push(Arc::new(SafeBrowsing::new(endpoint, api_key)));
+        }
+
+        checkers
+    }
+
+    pub fn push(&mut self, checker: Arc<dyn UrlChecker>) {
+        self.0.push(checker);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Unparseable URLs are left for link validation to report
+    pub async fn check(&self, url: &str) -> Option<String> {
+        let url = Url::parse(url).ok()?;
+
+        for checker in &self.0 {
+            if let Some(reason) = checker.check(&url).await {
+                return Some(reason);
+            }
+        }
+
+        None
+    }
+
+    // Like `check`, for a batch of URLs
+    pub async fn check_all(&self, urls: &[String]) -> Vec<Option<String>> {
+        let mut reasons: Vec<Option<String>> = vec![None; urls.len()];
+
+        for checker in &self.0 {
+            // Only what's parseable and not already flagged goes on to the next checker
+            let (indexes, unflagged): (Vec<usize>, Vec<Url>) = urls
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| reasons[*index].is_none())
+                .filter_map(|(index, url)| Url::parse(url).ok().map(|url| (index, url)))
+                .unzip();
+
+            if unflagged.is_empty() {
+                break;
+            }
+
+            for (index, reason) in indexes.into_iter().zip(checker.check_all(&unflagged).await) {
+                reasons[index] = reason;
+            }
+        }
+
+        reasons
+    }
+
+    // Runs every stored, enabled link back through the checkers and disables any that are now
+    // flagged. Connections are only held to load a batch and to disable what it flagged, not
+    // while the checkers run.
+    pub async fn recheck_links(&self, cache: &LinkCache, pool: &DbPool) -> Result<usize, String> {
+        let connect = move || async move {
+            DbConn::from_pool(pool)
+                .await
+                .ok_or_else(|| "Database connection unavailable".to_string())
+        };
+        let mut disabled = 0;
+        let mut last_id = 0;
+
+        loop {
+            let links = Link::find_enabled_after(last_id, RECHECK_BATCH_SIZE, &connect().await?)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let last_link = match links.last() {
+                Some(link) => link.id,
+                None => return Ok(disabled),
+            };
+
+            let urls: Vec<String> = links.iter().map(|link| link.url.clone()).collect();
+            let flagged: Vec<(Link, String)> = links
+                .into_iter()
+                .zip(self.check_all(&urls).await)
+                .filter_map(|(link, reason)| reason.map(|reason| (link, reason)))
+                .collect();
+
+            if !flagged.is_empty() {
+                let conn = connect().await?;
+
+                for (link, reason) in flagged {
+                    let link = link.disable(reason, &conn).await?;
+
+                    cache.invalidate(&link);
+                    disabled += 1;
+                }
+            }
+
+            last_id = last_link;
+        }
+    }
+
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_ignite("URL Checkers", |rocket| async {
+            rocket
+                .manage(UrlCheckers::from_env())
+                .attach(AdHoc::on_liftoff("Recheck Links", |rocket| {
+                    Box::pin(async move {
+                        let checkers = match rocket.state::<UrlCheckers>() {
+                            Some(checkers) if !checkers.is_empty() => checkers.clone(),
+                            _ => return,
+                        };
+                        let cache = match rocket.state::<LinkCache>() {
+                            Some(cache) => cache.clone(),
+                            None => return,
+                        };
+                        let pool = match DbConn::pool(rocket) {
+                            Some(pool) => pool.clone(),
+                            None => return,
+                        };
+                        let interval = std::env::var("URL_RECHECK_INTERVAL")
+                            .ok()
+                            .and_then(|seconds| seconds.parse::<u64>().ok())
+                            .unwrap_or(DEFAULT_RECHECK_INTERVAL);
+
+                        tokio::spawn(async move {
+                            loop {
+                                tokio::time::sleep(Duration::from_secs(interval)).await;
+
+                                if let Err(e) = checkers.recheck_links(&cache, &pool).await {
+                                    log::error!("Failed to recheck links: {}", e);
+                                }
+                            }
+                        });
+                    })
+                }))
+        })
+    }
+}
+
+// A plain text file with one domain per line, `#` starts a comment.
+// Subdomains of a listed domain are blocked too.
+pub struct DomainBlocklist {
+    path: PathBuf,
+    domains: RwLock<HashSet<String>>,
+}
+
+impl DomainBlocklist {
+    pub fn new(path: PathBuf) -> Self {
+        let blocklist = DomainBlocklist {
+            path,
+            domains: RwLock::new(HashSet::new()),
+        };
+
+        if let Err(e) = blocklist.reload() {
+            log::error!("Failed to load blocklist {}: {}", blocklist.path.display(), e);
+        }
+
+        blocklist
+    }
+
+    pub fn reload(&self) -> std::io::Result<usize> {
+        let contents = std::fs::read_to_string(&self.path)?;
+        let domains: HashSet<String> = contents
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim().to_lowercase())
+            .filter(|line| !line.is_empty())
+            .collect();
+        let count = domains.len();
+
+        *self.domains.write() = domains;
+
+        Ok(count)
+    }
+
+    // Reloads on SIGHUP, and on every tick of `interval` when one is given
+    pub fn watch(self: Arc<Self>, interval: Option<Duration>) {
+        #[cfg(unix)]
+        {
+            let blocklist = self.clone();
+
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let mut hangups = match signal(SignalKind::hangup()) {
+                    Ok(hangups) => hangups,
+                    Err(_) => return,
+                };
+
+                while hangups.recv().await.is_some() {
+                    blocklist.reload_or_log();
+                }
+            });
+        }
+
+        if let Some(interval) = interval {
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    self.reload_or_log();
+                }
+            });
+        }
+    }
+
+    fn reload_or_log(&self) {
+        if let Err(e) = self.reload() {
+            log::error!("Failed to reload blocklist {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        let domains = self.domains.read();
+        let mut host = host;
+
+        loop {
+            if domains.contains(host) {
+                return true;
+            }
+
+            match host.split_once('.') {
+                Some((_, parent)) => host = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl UrlChecker for DomainBlocklist {
+    async fn check(&self, url: &Url) -> Option<String> {
+        let host = url.host_str()?.to_lowercase();
+
+        if self.contains(&host) {
+            Some(format!("Host '{}' is blocklisted", host))
+        } else {
+            None
+        }
+    }
+}
+
+// Speaks the Safe Browsing v4 Lookup API, `endpoint` is only the scheme and host
+// so tests can point it at a local server
+pub struct SafeBrowsing {
+    client: reqwest::Client,
+    endpoint: String,
+    api_key: String,
+}
+
+impl SafeBrowsing {
+    pub fn new(endpoint: String, api_key: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(SAFE_BROWSING_TIMEOUT)
+            .build()
+            .expect("HTTP client");
+
+        SafeBrowsing {
+            client,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            api_key,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl UrlChecker for SafeBrowsing {
+    async fn check(&self, url: &Url) -> Option<String> {
+        self.check_all(std::slice::from_ref(url))
+            .await
+            .pop()
+            .flatten()
+    }
+
+    async fn check_all(&self, urls: &[Url]) -> Vec<Option<String>> {
+        let mut reasons = vec![];
+
+        for chunk in urls.chunks(MAX_THREAT_ENTRIES) {
+            let matches = self.find_threat_matches(chunk).await;
+
+            reasons.extend(chunk.iter().map(|url| {
+                matches
+                    .iter()
+                    .find(|threat| threat.threat.url == url.as_str())
+                    .map(|threat| format!("URL has been flagged as {}", threat.threat_type))
+            }));
+        }
+
+        reasons
+    }
+}
+
+impl SafeBrowsing {
+    // A lookup failure shouldn't stop links from being created, so it's logged and
+    // treated as no matches
+    async fn find_threat_matches(&self, urls: &[Url]) -> Vec<ThreatMatch> {
+        let request = ThreatMatchesRequest {
+            client: ClientInfo {
+                client_id: "kickshort".to_string(),
+                client_version: env!("CARGO_PKG_VERSION").to_string(),
+            },
+            threat_info: ThreatInfo {
+                threat_types: vec![
+                    "MALWARE".to_string(),
+                    "SOCIAL_ENGINEERING".to_string(),
+                    "UNWANTED_SOFTWARE".to_string(),
+                    "POTENTIALLY_HARMFUL_APPLICATION".to_string(),
+                ],
+                platform_types: vec!["ANY_PLATFORM".to_string()],
+                threat_entry_types: vec!["URL".to_string()],
+                threat_entries: urls
+                    .iter()
+                    .map(|url| ThreatEntry {
+                        url: url.to_string(),
+                    })
+                    .collect(),
+            },
+        };
+
+        let response = self
+            .client
+            .post(format!("{}/v4/threatMatches:find", self.endpoint))
+            .query(&[("key", &self.api_key)])
+            .json(&request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match response {
+            Ok(response) => match response.json::<ThreatMatchesResponse>().await {
+                Ok(matches) => matches.matches,
+                Err(e) => {
+                    log::warn!("Invalid Safe Browsing response: {}", e);
+                    vec![]
+                }
+            },
+            Err(e) => {
+                log::warn!("Safe Browsing lookup failed: {}", e);
+                vec![]
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatMatchesRequest {
+    client: ClientInfo,
+    threat_info: ThreatInfo,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientInfo {
+    client_id: String,
+    client_version: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatInfo {
+    threat_types: Vec<String>,
+    platform_types: Vec<String>,
+    threat_entry_types: Vec<String>,
+    threat_entries: Vec<ThreatEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ThreatEntry {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct ThreatMatchesResponse {
+    #[serde(default)]
+    matches: Vec<ThreatMatch>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThreatMatch {
+    threat_type: String,
+    threat: ThreatEntry,
+}
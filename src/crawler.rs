@@ -0,0 +1,142 @@
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use woothee::parser::Parser;
+
+// Link unfurlers used by chat apps and social networks, matched case-insensitively. These are
+// crawler tokens, in-app browsers like Pinterest's or Teams' send a full browser UA with the app's
+// name in it and have to be redirected like any other visitor.
+const UNFURLERS: &[&str] = &[
+    "slackbot",
+    "slack-imgproxy",
+    "facebookexternalhit",
+    "facebookcatalog",
+    "twitterbot",
+    "linkedinbot",
+    "discordbot",
+    "telegrambot",
+    "skypeuripreview",
+    "microsoftpreview",
+    "applebot",
+    "pinterestbot",
+    "redditbot",
+    "embedly",
+    "iframely",
+    "vkshare",
+    "mattermost-bot",
+    "google-pagerenderer",
+    "bitlybot",
+];
+
+// Catches what woothee's crawler list doesn't: HTTP libraries, link checkers and uptime monitors.
+// Matched as substrings, so they need to be specific enough not to hit phone models like "Cubot".
+const BOT_PATTERNS: &[&str] = &[
+    "bot/",
+    "crawl",
+    "spider",
+    "slurp",
+    "monitor",
+    "uptime",
+    "pingdom",
+    "statuscake",
+    "checker",
+    "validator",
+    "headless",
+    "curl",
+    "wget",
+    "httpie",
+    "python-requests",
+    "python-urllib",
+    "aiohttp",
+    "go-http-client",
+    "okhttp",
+    "java/",
+    "apache-httpclient",
+    "node-fetch",
+    "axios",
+    "libwww-perl",
+];
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Device {
+    pub kind: Option<String>,
+    pub os: Option<String>,
+    pub browser: Option<String>,
+}
+
+// Device type, OS and browser from the user agent, None for whatever woothee doesn't recognise
+pub fn device(user_agent: Option<&str>) -> Device {
+    let result = match user_agent.and_then(|user_agent| Parser::new().parse(user_agent)) {
+        Some(result) => result,
+        None => return Device::default(),
+    };
+    let known = |value: &str| match value {
+        "" | "UNKNOWN" => None,
+        value => Some(value.to_string()),
+    };
+    let kind = match result.category {
+        "pc" => Some("desktop".to_string()),
+        "smartphone" | "mobilephone" => Some("mobile".to_string()),
+        "crawler" => Some("bot".to_string()),
+        category => known(category),
+    };
+
+    Device {
+        kind,
+        os: known(result.os),
+        browser: known(result.name),
+    }
+}
+
+// WhatsApp's crawler sends a bare "WhatsApp/2.23.20.0 A", its in-app browser a Mozilla UA
+fn is_whatsapp_crawler(user_agent: &str) -> bool {
+    user_agent.starts_with("whatsapp/")
+}
+
+pub struct UserAgent(pub Option<String>);
+
+impl UserAgent {
+    pub fn is_unfurler(&self) -> bool {
+        match &self.0 {
+            Some(user_agent) => {
+                let user_agent = user_agent.to_lowercase();
+                is_whatsapp_crawler(&user_agent)
+                    || UNFURLERS
+                        .iter()
+                        .any(|unfurler| user_agent.contains(unfurler))
+            }
+            None => false,
+        }
+    }
+
+    // Unfurlers are bots too, and so is anything that doesn't send a user agent
+    pub fn is_bot(&self) -> bool {
+        let user_agent = match &self.0 {
+            Some(user_agent) if !user_agent.trim().is_empty() => user_agent,
+            _ => return true,
+        };
+        let lowercase = user_agent.to_lowercase();
+
+        if self.is_unfurler() || BOT_PATTERNS.iter().any(|pattern| lowercase.contains(pattern)) {
+            return true;
+        }
+
+        // woothee's patterns are case-sensitive
+        Parser::new()
+            .parse(user_agent)
+            .map_or(false, |result| result.category == "crawler")
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user_agent = request
+            .headers()
+            .get_one("User-Agent")
+            .map(|agent| agent.to_string());
+
+        Outcome::Success(UserAgent(user_agent))
+    }
+}
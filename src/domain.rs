@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use diesel::{
+    self,
+    prelude::*,
+    result::{DatabaseErrorKind, Error::DatabaseError},
+};
+use parking_lot::RwLock;
+use rocket::fairing::AdHoc;
+use rocket::request::{FromRequest, Outcome};
+use rocket::tokio;
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+use url::Host;
+
+use crate::DbConn;
+
+use self::schema::domains;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+// Every vanity domain, so short URLs and redirects don't need a query to resolve them.
+// Links without a domain belong to the primary domain from WHO_AM_I.
+static DOMAINS: RwLock<Vec<Domain>> = parking_lot::const_rwlock(Vec::new());
+
+#[derive(Queryable, Identifiable, Serialize, Deserialize, Clone, Debug)]
+#[table_name = "domains"]
+pub struct Domain {
+    pub id: i32,
+    pub host: String,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl Domain {
+    pub async fn all(conn: &DbConn) -> QueryResult<Vec<Domain>> {
+        conn.run(|c| domains::table.order(domains::host.asc()).load::<Self>(c))
+            .await
+    }
+
+    pub async fn find(id: i32, conn: &DbConn) -> Result<Domain, String> {
+        conn.run(move |c| {
+            domains::table
+                .find(id)
+                .get_result::<Self>(c)
+                .map_err(|_| "Domain not found".to_string())
+        })
+        .await
+    }
+
+    pub async fn insert(host: String, conn: &DbConn) -> Result<Domain, String> {
+        let host = host.trim().to_lowercase();
+
+        if host.is_empty() || Host::parse(&host).is_err() || host.contains('/') {
+            return Err("Invalid host".to_string());
+        }
+
+        let domain = conn
+            .run(move |c| {
+                diesel::insert_into(domains::table)
+                    .values(domains::host.eq(host))
+                    .get_result::<Self>(c)
+                    .map_err(|e| match e {
+                        DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                            "Domain already exists".to_string()
+                        }
+                        e => e.to_string(),
+                    })
+            })
+            .await?;
+
+        Domain::reload(conn).await.map_err(|e| e.to_string())?;
+
+        Ok(domain)
+    }
+
+    pub async fn delete(self, conn: &DbConn) -> Result<(), String> {
+        conn.run(move |c| {
+            diesel::delete(&self)
+                .execute(c)
+                .map_err(|e| match e {
+                    DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                        "Domain still has links".to_string()
+                    }
+                    e => e.to_string(),
+                })
+        })
+        .await?;
+
+        Domain::reload(conn).await.map_err(|e| e.to_string())
+    }
+
+    pub async fn delete_all(conn: &DbConn) -> QueryResult<usize> {
+        let deleted = conn
+            .run(|c| diesel::delete(domains::table).execute(c))
+            .await?;
+
+        Domain::reload(conn).await?;
+
+        Ok(deleted)
+    }
+
+    pub async fn reload(conn: &DbConn) -> QueryResult<()> {
+        let domains = Domain::all(conn).await?;
+
+        *DOMAINS.write() = domains;
+
+        Ok(())
+    }
+
+    // Matches with and without the port, since Host headers may include one
+    pub fn find_by_host(host: &str) -> Option<Domain> {
+        let host = host.to_lowercase();
+        let without_port = host.rsplit_once(':').map_or(host.as_str(), |(host, _)| host);
+
+        DOMAINS
+            .read()
+            .iter()
+            .find(|domain| domain.host == host || domain.host == without_port)
+            .cloned()
+    }
+
+    pub fn host_for(id: i32) -> Option<String> {
+        DOMAINS
+            .read()
+            .iter()
+            .find(|domain| domain.id == id)
+            .map(|domain| domain.host.clone())
+    }
+
+    pub fn hosts() -> Vec<String> {
+        DOMAINS.read().iter().map(|domain| domain.host.clone()).collect()
+    }
+
+    // Loads the domains before requests are served, then keeps them in sync
+    // with changes made by other instances
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_liftoff("Load Domains", |rocket| {
+            Box::pin(async move {
+                let pool = match DbConn::pool(rocket) {
+                    Some(pool) => pool.clone(),
+                    None => return,
+                };
+
+                if let Some(conn) = DbConn::from_pool(&pool).await {
+                    if let Err(e) = Domain::reload(&conn).await {
+                        log::error!("Failed to load domains: {}", e);
+                    }
+                }
+
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(RELOAD_INTERVAL).await;
+
+                        if let Some(conn) = DbConn::from_pool(&pool).await {
+                            if let Err(e) = Domain::reload(&conn).await {
+                                log::error!("Failed to reload domains: {}", e);
+                            }
+                        }
+                    }
+                });
+            })
+        })
+    }
+}
+
+// The domain a request was made to, None when it isn't one of the vanity domains
+pub struct RequestDomain(pub Option<Domain>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestDomain {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let domain = request
+            .headers()
+            .get_one("Host")
+            .and_then(Domain::find_by_host);
+
+        Outcome::Success(RequestDomain(domain))
+    }
+}
+
+pub mod schema {
+    table! {
+        domains (id) {
+            id -> Int4,
+            host -> Varchar,
+            created_at -> Timestamp,
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use maxminddb::{geoip2, Reader};
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Location {
+    pub country: Option<String>,
+    pub region: Option<String>,
+}
+
+// A local GeoLite2/GeoIP2 City or Country database, so lookups never leave the server
+#[derive(Clone)]
+pub struct GeoDatabase(Arc<Reader<Vec<u8>>>);
+
+impl GeoDatabase {
+    pub fn open(path: &str) -> Result<Self, String> {
+        Reader::open_readfile(path)
+            .map(|reader| GeoDatabase(Arc::new(reader)))
+            .map_err(|e| e.to_string())
+    }
+
+    // GEOIP_DATABASE_PATH points at the .mmdb file, lookups are skipped without it
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("GEOIP_DATABASE_PATH").ok()?;
+
+        match GeoDatabase::open(&path) {
+            Ok(database) => Some(database),
+            Err(e) => {
+                log::error!("Failed to open GeoIP database {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Location {
+        let city = match self.0.lookup::<geoip2::City>(ip) {
+            Ok(city) => city,
+            Err(_) => return Location::default(),
+        };
+
+        let country = city
+            .country
+            .and_then(|country| country.iso_code)
+            .map(|code| code.to_string());
+        let region = city
+            .subdivisions
+            .and_then(|subdivisions| subdivisions.into_iter().next())
+            .and_then(|subdivision| {
+                subdivision
+                    .names
+                    .and_then(|names| names.get("en").copied())
+                    .or(subdivision.iso_code)
+            })
+            .map(|region| region.to_string());
+
+        Location { country, region }
+    }
+}
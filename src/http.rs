@@ -0,0 +1,83 @@
+use std::{error::Error, net::SocketAddr, sync::Arc, time::Duration};
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use rocket::tokio;
+
+use crate::policy;
+
+const MAX_REDIRECTS: usize = 5;
+
+// For requests to URLs the operator configured, like click sinks
+pub fn client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS))
+        .user_agent(concat!("kickshort/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("HTTP client")
+}
+
+// For requests to URLs that API clients supply, like link destinations and webhooks.
+// URLs are checked against UrlPolicy when they're saved, but can still redirect to, or
+// resolve to, an internal address. Unless private hosts are allowed every redirect and
+// every resolved address is checked again before connecting.
+pub fn fetch_client(timeout: Duration, allow_private_hosts: bool) -> reqwest::Client {
+    let redirect = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if !allow_private_hosts && policy::is_private_url(attempt.url()) {
+            attempt.error("redirected to a private or local address")
+        } else {
+            attempt.follow()
+        }
+    });
+
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .redirect(redirect)
+        .user_agent(concat!("kickshort/", env!("CARGO_PKG_VERSION")));
+
+    if !allow_private_hosts {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+
+    builder.build().expect("HTTP client")
+}
+
+// Refuses host names with any private or local address so a public name can't point inside
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(resolve_public(name))
+    }
+}
+
+async fn resolve_public(name: Name) -> Result<Addrs, Box<dyn Error + Send + Sync>> {
+    let addresses = tokio::net::lookup_host((name.as_str(), 0))
+        .await?
+        .collect::<Vec<SocketAddr>>();
+
+    if addresses
+        .iter()
+        .any(|address| policy::is_private_ip(&address.ip()))
+    {
+        return Err(format!("{} resolves to a private or local address", name.as_str()).into());
+    }
+
+    Ok(Box::new(addresses.into_iter()))
+}
+
+// reqwest's own message leaves out why a redirect or connection was refused
+pub fn describe(error: reqwest::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+
+    message
+}
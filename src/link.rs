@@ -1,21 +1,42 @@
-use diesel::{self, prelude::*};
+use diesel::{
+    self,
+    prelude::*,
+    sql_types::{Integer, Nullable, Timestamp, Varchar},
+};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use rand::distributions::{Alphanumeric, DistString};
-
 use crate::{
+    canonical,
+    domain::Domain,
+    metadata::PageMetadata,
     paginate::Paginate,
+    policy::UrlPolicy,
+    short_code::{self, ShortCodeGenerator},
+    tag,
     DbConn,
 };
 
-use self::schema::links;
+use self::schema::{link_tags, links, tags};
+
+const CANONICAL_BACKFILL_BATCH_SIZE: i64 = 500;
 
-const HASH_FUDGE_LENGTH: usize = 6;
-const HASH_LENGTH: usize = 8;
+sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+sql_function!(fn coalesce(
+    x: diesel::sql_types::Nullable<diesel::sql_types::Integer>,
+    y: diesel::sql_types::Integer
+) -> diesel::sql_types::Integer);
 
 #[derive(
-    Queryable, Insertable, Serialize, Deserialize, Clone, AsChangeset, Identifiable, Debug,
+    Queryable,
+    QueryableByName,
+    Insertable,
+    Serialize,
+    Deserialize,
+    Clone,
+    AsChangeset,
+    Identifiable,
+    Debug,
 )]
 #[table_name = "links"]
 pub struct Link {
@@ -26,6 +47,47 @@ pub struct Link {
     pub visitors: i32,
     pub created_at: chrono::NaiveDateTime,
     pub title: Option<String>,
+    pub canonical_url: String,
+    pub disabled_at: Option<chrono::NaiveDateTime>,
+    pub disabled_reason: Option<String>,
+    pub deleted_at: Option<chrono::NaiveDateTime>,
+    pub domain_id: Option<i32>,
+    pub campaign_id: Option<i32>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub favicon_url: Option<String>,
+    pub metadata_fetched_at: Option<chrono::NaiveDateTime>,
+    pub interstitial: bool,
+}
+
+// Everything a link can be created with
+pub struct LinkAttributes {
+    pub url: String,
+    pub visible: bool,
+    pub custom_hash: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub domain_id: Option<i32>,
+    pub campaign_id: Option<i32>,
+    pub interstitial: bool,
+    // Should already be normalized with tag::normalize
+    pub tags: Vec<String>,
+}
+
+// Fields left as None keep their current value
+#[derive(Default)]
+pub struct LinkChanges {
+    pub url: Option<String>,
+    pub visible: Option<bool>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    // Some(None) takes the link out of its campaign
+    pub campaign_id: Option<Option<i32>>,
+    pub interstitial: Option<bool>,
+    // Replaces every tag, should already be normalized with tag::normalize
+    pub tags: Option<Vec<String>>,
 }
 
 impl Link {
@@ -33,12 +95,48 @@ impl Link {
         conn: &DbConn,
         page: i64,
         per_page: i64,
+        campaign_id: Option<i32>,
+        tag_filter: Option<TagFilter>,
     ) -> Result<(Vec<Link>, i64), diesel::result::Error> {
         // load all where visiblity true
         conn.run(move |c| {
-            links::table
+            let mut query = links::table
                 .filter(links::visible.eq(true))
+                .filter(links::deleted_at.is_null())
                 .order(links::created_at.desc())
+                .into_boxed();
+
+            if let Some(campaign_id) = campaign_id {
+                query = query.filter(links::campaign_id.eq(campaign_id));
+            }
+
+            match tag_filter {
+                Some(TagFilter::Any(names)) => {
+                    query = query.filter(
+                        links::id.eq_any(
+                            link_tags::table
+                                .inner_join(tags::table)
+                                .filter(tags::name.eq_any(names))
+                                .select(link_tags::link_id),
+                        ),
+                    );
+                }
+                Some(TagFilter::All(names)) => {
+                    for name in names {
+                        query = query.filter(
+                            links::id.eq_any(
+                                link_tags::table
+                                    .inner_join(tags::table)
+                                    .filter(tags::name.eq(name))
+                                    .select(link_tags::link_id),
+                            ),
+                        );
+                    }
+                }
+                None => {}
+            }
+
+            query
                 .paginate(page)
                 .per_page(per_page)
                 .load_and_count_pages(c)
@@ -46,56 +144,90 @@ impl Link {
         .await
     }
 
-    pub async fn insert(
-        url: String,
-        visible: bool,
-        custom_hash: Option<String>,
-        title: Option<String>,
-        conn: &DbConn,
-    ) -> LinkResult {
-        let trimmed_url = url.trim_end_matches('/').to_string();
-
-        let hash = match custom_hash.clone() {
-            Some(hash) => {
-                let link = Link::find_by_hash(hash.clone(), conn).await;
-                if link.is_ok() {
-                    return Err("URL has already been taken".to_string());
-                }
-
-                hash
-            }
-            None => {
-                let mut hash = hash_url(&trimmed_url);
+    pub async fn insert(attributes: LinkAttributes, conn: &DbConn) -> Result<Link, InsertError> {
+        Link::insert_with(attributes, short_code::from_env().as_ref(), conn).await
+    }
 
-                while Link::find_by_hash(hash.clone(), conn).await.is_ok() {
-                    hash = hash_url(&trimmed_url);
-                }
+    // Generated hashes that turn out to be taken are retried up to short_code::MAX_ATTEMPTS times
+    pub async fn insert_with(
+        attributes: LinkAttributes,
+        generator: &dyn ShortCodeGenerator,
+        conn: &DbConn,
+    ) -> Result<Link, InsertError> {
+        let errors = validate(
+            &attributes.url,
+            attributes.title.as_ref(),
+            attributes.description.as_ref(),
+            attributes.image_url.as_ref(),
+        );
+
+        if !errors.is_empty() {
+            return Err(InsertError::Invalid(errors.join(", ")));
+        }
 
-                hash
-            }
+        let canonical_url = canonical::canonicalize(&attributes.url)
+            .unwrap_or_else(|| attributes.url.clone());
+        let mut new_link = NewLink {
+            url: attributes.url,
+            hash: String::new(),
+            visible: attributes.visible,
+            title: attributes.title,
+            canonical_url,
+            domain_id: attributes.domain_id,
+            campaign_id: attributes.campaign_id,
+            description: attributes.description,
+            image_url: attributes.image_url,
+            interstitial: attributes.interstitial,
         };
 
-        let new_link = NewLink {
-            url: trimmed_url,
-            hash,
-            visible,
-            title,
-        };
+        let tags = attributes.tags;
 
-        conn.run(move |c| {
-            let query = diesel::insert_into(links::table).values(&new_link);
-            let link = match query.get_result::<Self>(c) {
-                Ok(link) => link,
-                Err(_) => return Err("Link not found".to_string()),
+        if let Some(hash) = attributes.custom_hash {
+            new_link.hash = hash;
+
+            return match Link::insert_new_link(new_link, tags, conn).await {
+                Ok(Some(link)) => Ok(link),
+                Ok(None) => Err(InsertError::HashTaken),
+                Err(e) => Err(InsertError::Database(e.to_string())),
             };
+        }
 
-            let errors = link.validate();
+        for _ in 0..short_code::MAX_ATTEMPTS {
+            new_link.hash = generator.generate(conn).await.map_err(InsertError::Database)?;
 
-            if !errors.is_empty() {
-                return Err(errors.join(", "));
+            match Link::insert_new_link(new_link.clone(), tags.clone(), conn).await {
+                Ok(Some(link)) => return Ok(link),
+                Ok(None) => continue,
+                Err(e) => return Err(InsertError::Database(e.to_string())),
             }
+        }
+
+        Err(InsertError::Database(
+            "Could not generate a unique hash, please try again".to_string(),
+        ))
+    }
+
+    // Leaves uniqueness to the hash_unique index, so concurrent inserts can't both claim a hash.
+    // Returns None when the hash is already taken, including by deleted links that haven't been purged.
+    async fn insert_new_link(
+        new_link: NewLink,
+        tags: Vec<String>,
+        conn: &DbConn,
+    ) -> QueryResult<Option<Link>> {
+        conn.run(move |c| {
+            c.transaction(|| {
+                let link = diesel::insert_into(links::table)
+                    .values(&new_link)
+                    .on_conflict_do_nothing()
+                    .get_result::<Self>(c)
+                    .optional()?;
+
+                if let Some(link) = &link {
+                    tag::replace(link.id, &tags, c)?;
+                }
 
-            Ok(link)
+                Ok(link)
+            })
         })
         .await
     }
@@ -104,98 +236,392 @@ impl Link {
         conn.run(move |c| {
             links::table
                 .find(id)
+                .filter(links::deleted_at.is_null())
+                .get_result::<Self>(c)
+                .map_err(|_| "Link not found".to_string())
+        })
+        .await
+    }
+
+    // Hashes are case-insensitive and scoped to a domain, matching the hash_unique index.
+    // A domain_id of None is the primary domain.
+    // Redirects should go through LinkCache::find_by_hash instead
+    pub async fn find_by_hash(
+        hash: String,
+        domain_id: Option<i32>,
+        conn: &DbConn,
+    ) -> QueryResult<Option<Link>> {
+        conn.run(move |c| {
+            // Written exactly as the index expression, `COALESCE(domain_id, 0)`, or it isn't used.
+            // The 0 has to be a literal rather than a bind parameter for the same reason.
+            let primary_domain = diesel::dsl::sql::<diesel::sql_types::Integer>("0");
+
+            links::table
+                .filter(coalesce(links::domain_id, primary_domain).eq(domain_id.unwrap_or(0)))
+                .filter(lower(links::hash).eq(hash.to_lowercase()))
+                .filter(links::deleted_at.is_null())
+                .first::<Self>(c)
+                .optional()
+        })
+        .await
+    }
+
+    pub async fn find_deleted(id: i32, conn: &DbConn) -> LinkResult {
+        conn.run(move |c| {
+            links::table
+                .find(id)
+                .filter(links::deleted_at.is_not_null())
                 .get_result::<Self>(c)
                 .map_err(|_| "Link not found".to_string())
         })
         .await
     }
 
-    pub async fn find_by_hash(hash: String, conn: &DbConn) -> LinkResult {
+    pub async fn find_enabled_after(
+        id: i32,
+        limit: i64,
+        conn: &DbConn,
+    ) -> QueryResult<Vec<Link>> {
         conn.run(move |c| {
-            let link = match links::table.filter(links::hash.eq(hash)).first::<Self>(c) {
-                Ok(link) => link,
-                Err(_) => return Err("Link not found".to_string()),
+            links::table
+                .filter(links::id.gt(id))
+                .filter(links::disabled_at.is_null())
+                .filter(links::deleted_at.is_null())
+                .order(links::id.asc())
+                .limit(limit)
+                .load::<Self>(c)
+        })
+        .await
+    }
+
+    // Fills in canonical_url for links that don't have one yet, returns how many were updated
+    pub async fn backfill_canonical_urls(conn: &DbConn) -> QueryResult<usize> {
+        let mut updated = 0;
+        let mut last_id = 0;
+
+        loop {
+            let batch = conn
+                .run(move |c| {
+                    links::table
+                        .select((links::id, links::url))
+                        .filter(links::canonical_url.eq(""))
+                        .filter(links::id.gt(last_id))
+                        .order(links::id.asc())
+                        .limit(CANONICAL_BACKFILL_BATCH_SIZE)
+                        .load::<(i32, String)>(c)
+                })
+                .await?;
+
+            last_id = match batch.last() {
+                Some((id, _)) => *id,
+                None => return Ok(updated),
             };
 
-            Ok(link)
+            updated += conn
+                .run(move |c| {
+                    c.transaction::<_, diesel::result::Error, _>(|| {
+                        let mut updated = 0;
+
+                        for (id, url) in batch {
+                            let canonical_url =
+                                canonical::canonicalize(&url).unwrap_or_else(|| url.clone());
+
+                            updated += diesel::update(links::table.find(id))
+                                .filter(links::canonical_url.eq(""))
+                                .set(links::canonical_url.eq(canonical_url))
+                                .execute(c)?;
+                        }
+
+                        Ok(updated)
+                    })
+                })
+                .await?;
+        }
+    }
+
+    // Only writes the changed columns, so it can't undo visits or edits made since the link was loaded
+    pub async fn update(self, changes: LinkChanges, conn: &DbConn) -> LinkResult {
+        let errors = validate(
+            changes.url.as_ref().unwrap_or(&self.url),
+            changes.title.as_ref().or(self.title.as_ref()),
+            changes.description.as_ref().or(self.description.as_ref()),
+            changes.image_url.as_ref().or(self.image_url.as_ref()),
+        );
+
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+
+        let changeset = LinkChangeset {
+            canonical_url: changes
+                .url
+                .as_ref()
+                .map(|url| canonical::canonicalize(url).unwrap_or_else(|| url.clone())),
+            url: changes.url,
+            visible: changes.visible,
+            title: changes.title,
+            description: changes.description,
+            image_url: changes.image_url,
+            campaign_id: changes.campaign_id,
+            interstitial: changes.interstitial,
+        };
+
+        let id = self.id;
+        let tags = changes.tags;
+
+        conn.run(move |c| {
+            c.transaction(|| {
+                let link = links::table.find(id).filter(links::deleted_at.is_null());
+                let link = if changeset.is_empty() {
+                    link.get_result::<Self>(c)?
+                } else {
+                    diesel::update(link).set(&changeset).get_result::<Self>(c)?
+                };
+
+                if let Some(tags) = tags {
+                    tag::replace(link.id, &tags, c)?;
+                }
+
+                Ok(link)
+            })
+            .map_err(|e: diesel::result::Error| e.to_string())
         })
         .await
     }
 
-    pub async fn increment_visitors(self, conn: &DbConn) -> bool {
+    // Only fills in columns that are still blank when the fetch finishes, checked in the UPDATE
+    // itself since the link may have been edited or visited while the page was being fetched.
+    // Returns None when the link was deleted in the meantime.
+    pub async fn apply_metadata(
+        self,
+        metadata: PageMetadata,
+        conn: &DbConn,
+    ) -> QueryResult<Option<Link>> {
+        let id = self.id;
+        let title = metadata.title.map(|title| truncate(title, 255));
+        let description = metadata.description.map(|description| truncate(description, 1000));
+
+        conn.run(move |c| {
+            diesel::sql_query(
+                "UPDATE links SET \
+                 title = CASE WHEN NULLIF(TRIM(title), '') IS NULL THEN $1 ELSE title END, \
+                 description = CASE WHEN NULLIF(TRIM(description), '') IS NULL \
+                   THEN $2 ELSE description END, \
+                 image_url = CASE WHEN NULLIF(TRIM(image_url), '') IS NULL \
+                   THEN $3 ELSE image_url END, \
+                 favicon_url = CASE WHEN NULLIF(TRIM(favicon_url), '') IS NULL \
+                   THEN $4 ELSE favicon_url END, \
+                 metadata_fetched_at = $5 \
+                 WHERE id = $6 AND deleted_at IS NULL \
+                 RETURNING *",
+            )
+            .bind::<Nullable<Varchar>, _>(title)
+            .bind::<Nullable<Varchar>, _>(description)
+            .bind::<Nullable<Varchar>, _>(metadata.image_url)
+            .bind::<Nullable<Varchar>, _>(metadata.favicon_url)
+            .bind::<Timestamp, _>(chrono::Utc::now().naive_utc())
+            .bind::<Integer, _>(id)
+            .get_result::<Self>(c)
+            .optional()
+        })
+        .await
+    }
+
+    pub async fn disable(self, reason: String, conn: &DbConn) -> LinkResult {
         conn.run(move |c| {
             diesel::update(&self)
-                .set(links::visitors.eq(links::visitors + 1))
-                .execute(c)
-                .is_ok()
+                .set((
+                    links::disabled_at.eq(Some(chrono::Utc::now().naive_utc())),
+                    links::disabled_reason.eq(Some(reason)),
+                ))
+                .get_result::<Self>(c)
+                .map_err(|e| e.to_string())
         })
         .await
     }
 
-    pub async fn delete(self, conn: &DbConn) -> bool {
-        conn.run(move |c| diesel::delete(&self).execute(c).is_ok())
-            .await
+    pub async fn enable(self, conn: &DbConn) -> LinkResult {
+        conn.run(move |c| {
+            diesel::update(&self)
+                .set((
+                    links::disabled_at.eq(None::<chrono::NaiveDateTime>),
+                    links::disabled_reason.eq(None::<String>),
+                ))
+                .get_result::<Self>(c)
+                .map_err(|e| e.to_string())
+        })
+        .await
     }
 
-    pub async fn delete_all(conn: &DbConn) -> QueryResult<usize> {
-        conn.run(move |c| diesel::delete(links::table).execute(c))
-            .await
+    pub fn is_disabled(&self) -> bool {
+        self.disabled_at.is_some()
     }
 
-    pub async fn save(self, conn: &DbConn) -> LinkResult {
-        conn.run(move |c| match self.save_changes(c) {
-            Ok(link) => Ok(link),
-            Err(e) => Err(e.to_string()),
+    pub async fn delete(self, conn: &DbConn) -> bool {
+        conn.run(move |c| {
+            diesel::update(&self)
+                .set(links::deleted_at.eq(Some(chrono::Utc::now().naive_utc())))
+                .execute(c)
+                .is_ok()
         })
         .await
     }
 
-    pub fn redirect_url(&self) -> String {
-        let who_am_i = std::env::var("WHO_AM_I").expect("WHO_AM_I must be set");
+    pub async fn restore(self, conn: &DbConn) -> LinkResult {
+        conn.run(move |c| {
+            diesel::update(&self)
+                .set(links::deleted_at.eq(None::<chrono::NaiveDateTime>))
+                .get_result::<Self>(c)
+                .map_err(|e| e.to_string())
+        })
+        .await
+    }
 
-        format!("{}/{}", who_am_i, self.hash)
+    pub async fn purge_deleted(
+        deleted_before: chrono::NaiveDateTime,
+        conn: &DbConn,
+    ) -> QueryResult<usize> {
+        conn.run(move |c| {
+            diesel::delete(links::table.filter(links::deleted_at.lt(deleted_before))).execute(c)
+        })
+        .await
     }
 
-    fn validate(&self) -> Vec<String> {
-        let mut errors = vec![];
+    pub async fn delete_all(conn: &DbConn) -> QueryResult<usize> {
+        conn.run(move |c| diesel::delete(links::table).execute(c))
+            .await
+    }
 
-        if self.url.is_empty() {
-            errors.push("URL cannot be empty".to_string());
-        } else if Url::parse(&self.url).is_err() {
-            errors.push("Invalid URL".to_string());
+    pub fn redirect_url(&self) -> String {
+        if let Some(host) = self.domain_id.and_then(Domain::host_for) {
+            return format!("https://{}/{}", host, self.hash);
         }
 
-        if let Some(title) = &self.title {
-            if title.len() > 255 {
-                errors.push("Title cannot be over 255 characters".to_string());
-            }
-        }
+        let who_am_i = std::env::var("WHO_AM_I").expect("WHO_AM_I must be set");
 
-        errors
+        format!("{}/{}", who_am_i, self.hash)
     }
-}
 
-fn hash_url(url: &String) -> String {
-    let random_fudge = Alphanumeric.sample_string(&mut rand::thread_rng(), HASH_FUDGE_LENGTH);
-    let fudged_url = format!("{}{}", random_fudge, url);
-    let unsafe_hash = base64_url::encode(&fudged_url);
-    let mut url_safe_hash = base64_url::escape(&unsafe_hash).to_string().to_lowercase();
-    url_safe_hash.truncate(HASH_LENGTH);
+    // What QR codes encode, marked so scans are counted apart from other visits
+    pub fn scan_url(&self) -> String {
+        format!("{}?qr", self.redirect_url())
+    }
+
+    // Served by the API rather than the link's domain, so it doesn't depend on vanity domain setup
+    pub fn qr_url(&self) -> String {
+        let who_am_i = std::env::var("WHO_AM_I").expect("WHO_AM_I must be set");
 
-    url_safe_hash
+        format!("{}/api/links/{}/qr.svg", who_am_i, self.id)
+    }
 }
 
-#[derive(Serialize, Deserialize, Insertable)]
+#[derive(Serialize, Deserialize, Insertable, Clone)]
 #[table_name = "links"]
 struct NewLink {
     url: String,
     hash: String,
     visible: bool,
     title: Option<String>,
+    canonical_url: String,
+    domain_id: Option<i32>,
+    campaign_id: Option<i32>,
+    description: Option<String>,
+    image_url: Option<String>,
+    interstitial: bool,
+}
+
+// Columns left as None aren't written
+#[derive(AsChangeset)]
+#[table_name = "links"]
+struct LinkChangeset {
+    url: Option<String>,
+    canonical_url: Option<String>,
+    visible: Option<bool>,
+    title: Option<String>,
+    description: Option<String>,
+    image_url: Option<String>,
+    campaign_id: Option<Option<i32>>,
+    interstitial: Option<bool>,
+}
+
+impl LinkChangeset {
+    fn is_empty(&self) -> bool {
+        self.url.is_none()
+            && self.visible.is_none()
+            && self.title.is_none()
+            && self.description.is_none()
+            && self.image_url.is_none()
+            && self.campaign_id.is_none()
+            && self.interstitial.is_none()
+    }
+}
+
+fn validate(
+    url: &str,
+    title: Option<&String>,
+    description: Option<&String>,
+    image_url: Option<&String>,
+) -> Vec<String> {
+    let mut errors = vec![];
+
+    if url.is_empty() {
+        errors.push("URL cannot be empty".to_string());
+    } else {
+        match Url::parse(url) {
+            Ok(url) => errors.extend(UrlPolicy::from_env().check(&url)),
+            Err(_) => errors.push("Invalid URL".to_string()),
+        }
+    }
+
+    if let Some(title) = title {
+        if title.len() > 255 {
+            errors.push("Title cannot be over 255 characters".to_string());
+        }
+    }
+
+    if let Some(description) = description {
+        if description.len() > 1000 {
+            errors.push("Description cannot be over 1000 characters".to_string());
+        }
+    }
+
+    if let Some(image_url) = image_url {
+        if !image_url.is_empty() && Url::parse(image_url).is_err() {
+            errors.push("Invalid image URL".to_string());
+        }
+    }
+
+    errors
+}
+
+fn truncate(mut value: String, max_length: usize) -> String {
+    if value.len() > max_length {
+        let mut end = max_length;
+
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        value.truncate(end);
+    }
+
+    value
 }
 
 pub type LinkResult = Result<Link, String>;
 
+pub enum TagFilter {
+    Any(Vec<String>),
+    All(Vec<String>),
+}
+
+#[derive(Debug)]
+pub enum InsertError {
+    HashTaken,
+    Invalid(String),
+    Database(String),
+}
+
 pub mod schema {
     table! {
         links (id) {
@@ -206,6 +632,36 @@ pub mod schema {
             visitors -> Int4,
             created_at -> Timestamp,
             title -> Nullable<Varchar>,
+            canonical_url -> Varchar,
+            disabled_at -> Nullable<Timestamp>,
+            disabled_reason -> Nullable<Varchar>,
+            deleted_at -> Nullable<Timestamp>,
+            domain_id -> Nullable<Int4>,
+            campaign_id -> Nullable<Int4>,
+            description -> Nullable<Varchar>,
+            image_url -> Nullable<Varchar>,
+            favicon_url -> Nullable<Varchar>,
+            metadata_fetched_at -> Nullable<Timestamp>,
+            interstitial -> Bool,
         }
     }
+
+    table! {
+        tags (id) {
+            id -> Int4,
+            name -> Varchar,
+        }
+    }
+
+    table! {
+        link_tags (link_id, tag_id) {
+            link_id -> Int4,
+            tag_id -> Int4,
+        }
+    }
+
+    joinable!(link_tags -> links (link_id));
+    joinable!(link_tags -> tags (tag_id));
+
+    allow_tables_to_appear_in_same_query!(links, tags, link_tags);
 }
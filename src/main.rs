@@ -1,7 +1,25 @@
+mod alias;
 mod api;
+mod campaign;
+mod cache;
+mod canonical;
+mod checker;
 mod cors;
+mod crawler;
+mod domain;
+mod geo;
+mod http;
 mod link;
+mod metadata;
 mod paginate;
+mod policy;
+mod qr;
+mod retention;
+mod short_code;
+mod sink;
+mod tag;
+mod visit;
+mod webhook;
 
 #[cfg(test)]
 mod tests;
@@ -15,29 +33,76 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
+use crate::alias::AliasRules;
+use crate::cache::{CacheStats, LinkCache};
 use crate::api::*;
+use crate::campaign::Campaign;
+use crate::checker::UrlCheckers;
 use crate::cors::Cors;
+use crate::crawler::UserAgent;
+use crate::domain::{Domain, RequestDomain};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use link::Link;
+use link::{InsertError, Link, LinkAttributes, LinkChanges, TagFilter};
+use metadata::MetadataFetcher;
+use qr::QrOptions;
+use sink::{ClickEvent, ClickSinks, SinkStats};
+use tag::TagCount;
+use visit::{
+    Channel, ClientIp, GroupCount, NewVisit, Referrer, TrustedProxies, VisitBreakdown,
+    VisitRecorder,
+};
+use webhook::{Delivery, Event, Webhook, WebhookChanges};
 use map_macro::map;
 use rocket::fairing::AdHoc;
 use rocket::fs::FileServer;
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
-use rocket::{Build, Rocket};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Build, Request, Rocket, State};
 use rocket_dyn_templates::{context, Template};
 
 #[cfg_attr(not(test), database("url_shorten"))]
 #[cfg_attr(test, database("url_shorten_test"))]
 pub struct DbConn(diesel::PgConnection);
 
-#[get("/?<page>&<per_page>", format = "application/json")]
+pub type DbPool = rocket_sync_db_pools::ConnectionPool<DbConn, PgConnection>;
+
+impl DbConn {
+    // For background tasks, which can't hold on to a connection between runs
+    pub async fn from_pool(pool: &DbPool) -> Option<Self> {
+        pool.get().await.map(DbConn)
+    }
+}
+
+// The pool rather than a connection, for routes that only need one some of the time
+pub struct Pool<'r>(pub &'r DbPool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Pool<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match DbConn::pool(request.rocket()) {
+            Some(pool) => Outcome::Success(Pool(pool)),
+            None => Outcome::Failure((Status::ServiceUnavailable, ())),
+        }
+    }
+}
+
+// `tags` is comma separated, `tag_match` is "any" (the default) or "all"
+#[get(
+    "/?<page>&<per_page>&<campaign>&<tags>&<tag_match>",
+    format = "application/json"
+)]
 async fn index(
     conn: DbConn,
     page: Option<String>,
     per_page: Option<String>,
+    campaign: Option<String>,
+    tags: Option<String>,
+    tag_match: Option<String>,
     _api_key: APIKey,
 ) -> Result<Json<PaginatedLinkResponse>, Status> {
     let parsed_page = match page {
@@ -56,7 +121,40 @@ async fn index(
         None => paginate::DEFAULT_PER_PAGE,
     };
 
-    match Link::paginate(&conn, parsed_page, parsed_per_page).await {
+    let parsed_campaign = match campaign {
+        Some(campaign) => match campaign.parse::<i32>() {
+            Ok(campaign) => Some(campaign),
+            Err(_) => return Err(Status::BadRequest),
+        },
+        None => None,
+    };
+
+    let tag_filter = match tags {
+        Some(tags) => {
+            let names: Vec<String> = tags.split(',').map(|name| name.to_string()).collect();
+            let names = match tag::normalize(&names) {
+                Ok(names) => names,
+                Err(_) => return Err(Status::BadRequest),
+            };
+
+            match tag_match.as_deref() {
+                None | Some("any") => Some(TagFilter::Any(names)),
+                Some("all") => Some(TagFilter::All(names)),
+                Some(_) => return Err(Status::BadRequest),
+            }
+        }
+        None => None,
+    };
+
+    match Link::paginate(
+        &conn,
+        parsed_page,
+        parsed_per_page,
+        parsed_campaign,
+        tag_filter,
+    )
+    .await
+    {
         Ok(paginated_links) => {
             let (links, last_page) = paginated_links;
 
@@ -79,55 +177,731 @@ async fn index(
 
 #[get("/<id>", format = "application/json")]
 async fn show(id: i32, conn: DbConn, _api_key: APIKey) -> APIResult {
-    match Link::find(id, &conn).await {
-        Ok(link) => APIResult::ok(link),
-        Err(_) => APIResult::not_found("Link not found".to_string()),
+    let link = match Link::find(id, &conn).await {
+        Ok(link) => link,
+        Err(_) => return APIResult::not_found("Link not found".to_string()),
+    };
+
+    match LinkResponse::build(link, &conn).await {
+        Ok(response) => APIResult::ok(response),
+        Err(e) => APIResult::internal_server_error(e.to_string()),
+    }
+}
+
+#[get("/<id>/breakdown", format = "application/json")]
+async fn breakdown(
+    id: i32,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<VisitBreakdown>, APIResult> {
+    Link::find(id, &conn)
+        .await
+        .map_err(|_| APIResult::not_found("Link not found".to_string()))?;
+
+    match visit::breakdown(id, &conn).await {
+        Ok(breakdown) => Ok(Json(breakdown)),
+        Err(e) => Err(APIResult::internal_server_error(e.to_string())),
+    }
+}
+
+// `from` and `to` are YYYY-MM-DD and both optional, `limit` defaults to 10
+#[get("/<id>/referrers?<from>&<to>&<limit>", format = "application/json")]
+async fn referrers(
+    id: i32,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<i64>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<Vec<GroupCount>>, APIResult> {
+    Link::find(id, &conn)
+        .await
+        .map_err(|_| APIResult::not_found("Link not found".to_string()))?;
+
+    let parse_date = |date: Option<&str>| match date {
+        Some(date) => chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| APIResult::unprocessable_entity(format!("Invalid date '{}'", date))),
+        None => Ok(None),
+    };
+    let from = parse_date(from)?;
+    let to = parse_date(to)?;
+    let limit = limit.unwrap_or(10).clamp(1, 100);
+
+    match visit::top_referrers(id, from, to, limit, &conn).await {
+        Ok(referrers) => Ok(Json(referrers)),
+        Err(e) => Err(APIResult::internal_server_error(e.to_string())),
+    }
+}
+
+#[get("/<id>/qr.svg?<size>&<margin>&<ec>&<fg>&<bg>")]
+#[allow(clippy::too_many_arguments)]
+async fn qr_svg(
+    id: i32,
+    size: Option<u32>,
+    margin: Option<u32>,
+    ec: Option<&str>,
+    fg: Option<&str>,
+    bg: Option<&str>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<(ContentType, String), APIResult> {
+    let link = Link::find(id, &conn)
+        .await
+        .map_err(|_| APIResult::not_found("Link not found".to_string()))?;
+    let options = QrOptions::from_params(size, margin, ec, fg, bg)
+        .map_err(APIResult::unprocessable_entity)?;
+
+    match qr::svg(&link.scan_url(), &options) {
+        Ok(svg) => Ok((ContentType::SVG, svg)),
+        Err(e) => Err(APIResult::internal_server_error(e)),
+    }
+}
+
+#[get("/<id>/qr.png?<size>&<margin>&<ec>&<fg>&<bg>")]
+#[allow(clippy::too_many_arguments)]
+async fn qr_png(
+    id: i32,
+    size: Option<u32>,
+    margin: Option<u32>,
+    ec: Option<&str>,
+    fg: Option<&str>,
+    bg: Option<&str>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<(ContentType, Vec<u8>), APIResult> {
+    let link = Link::find(id, &conn)
+        .await
+        .map_err(|_| APIResult::not_found("Link not found".to_string()))?;
+    let options = QrOptions::from_params(size, margin, ec, fg, bg)
+        .map_err(APIResult::unprocessable_entity)?;
+
+    match qr::png(&link.scan_url(), &options) {
+        Ok(png) => Ok((ContentType::PNG, png)),
+        Err(e) => Err(APIResult::internal_server_error(e)),
     }
 }
 
 #[post("/", data = "<link_data>", format = "application/json")]
-async fn new(link_data: Json<LinkRequest>, conn: DbConn, _api_key: APIKey) -> APIResult {
-    let url = link_data.url.trim_end_matches('/').to_string();
-    let visible = link_data.visible;
-    let custom_hash = link_data.custom_hash.clone();
-    let title = link_data.title.clone();
-
-    match Link::insert(url, visible, custom_hash, title, &conn).await {
-        Ok(link) => APIResult::created(link),
+async fn new(
+    link_data: Json<LinkRequest>,
+    conn: DbConn,
+    checkers: &State<UrlCheckers>,
+    alias_rules: &State<AliasRules>,
+    metadata_fetcher: &State<MetadataFetcher>,
+    cache: &State<LinkCache>,
+    _api_key: APIKey,
+) -> APIResult {
+    let link_data = link_data.into_inner();
+
+    let custom_hash = match &link_data.custom_hash {
+        Some(alias) => match alias_rules.normalize(alias) {
+            Ok(alias) => Some(alias),
+            Err(error) => return APIResult::unprocessable_entity(error),
+        },
+        None => None,
+    };
+
+    let domain_id = match &link_data.domain {
+        Some(host) => match Domain::find_by_host(host) {
+            Some(domain) => Some(domain.id),
+            None => return APIResult::unprocessable_entity("Unknown domain".to_string()),
+        },
+        None => None,
+    };
+
+    if let Some(campaign_id) = link_data.campaign_id {
+        if Campaign::find(campaign_id, &conn).await.is_err() {
+            return APIResult::unprocessable_entity("Campaign not found".to_string());
+        }
+    }
+
+    if let Some(reason) = checkers.check(&link_data.url).await {
+        return APIResult::unprocessable_entity(reason);
+    }
+
+    let tags = match tag::normalize(link_data.tags.as_deref().unwrap_or_default()) {
+        Ok(tags) => tags,
+        Err(error) => return APIResult::unprocessable_entity(error),
+    };
+
+    let attributes = LinkAttributes {
+        url: link_data.url,
+        visible: link_data.visible,
+        custom_hash,
+        title: link_data.title,
+        description: link_data.description,
+        image_url: link_data.image_url,
+        domain_id,
+        campaign_id: link_data.campaign_id,
+        interstitial: link_data.interstitial.unwrap_or(false),
+        tags,
+    };
+
+    match Link::insert(attributes, &conn).await {
+        Ok(link) => {
+            // The hash may have been cached as unknown
+            cache.invalidate(&link);
+
+            if link_data.fetch_metadata.unwrap_or(false) {
+                metadata_fetcher.enqueue(link.id);
+            }
+
+            let response = match LinkResponse::build(link, &conn).await {
+                Ok(response) => response,
+                Err(e) => return APIResult::internal_server_error(e.to_string()),
+            };
+
+            webhook::enqueue(Event::Created, &response, &conn).await;
+
+            APIResult::created(response)
+        }
+        Err(InsertError::HashTaken) => APIResult::conflict("Alias already taken".to_string()),
+        Err(InsertError::Invalid(error)) => APIResult::unprocessable_entity(error),
+        Err(InsertError::Database(error)) => APIResult::internal_server_error(error),
+    }
+}
+
+#[put("/<id>", data = "<link_data>", format = "application/json")]
+async fn update(
+    id: i32,
+    link_data: Json<LinkUpdateRequest>,
+    conn: DbConn,
+    checkers: &State<UrlCheckers>,
+    cache: &State<LinkCache>,
+    _api_key: APIKey,
+) -> APIResult {
+    let link = match Link::find(id, &conn).await {
+        Ok(link) => link,
+        Err(_) => return APIResult::not_found("Link not found".to_string()),
+    };
+
+    let link_data = link_data.into_inner();
+
+    if let Some(Some(campaign_id)) = link_data.campaign_id {
+        if Campaign::find(campaign_id, &conn).await.is_err() {
+            return APIResult::unprocessable_entity("Campaign not found".to_string());
+        }
+    }
+
+    if let Some(url) = &link_data.url {
+        if let Some(reason) = checkers.check(url).await {
+            return APIResult::unprocessable_entity(reason);
+        }
+    }
+
+    let tags = match link_data.tags.as_deref().map(tag::normalize) {
+        Some(Ok(tags)) => Some(tags),
+        Some(Err(error)) => return APIResult::unprocessable_entity(error),
+        None => None,
+    };
+
+    let changes = LinkChanges {
+        url: link_data.url,
+        visible: link_data.visible,
+        title: link_data.title,
+        description: link_data.description,
+        image_url: link_data.image_url,
+        campaign_id: link_data.campaign_id,
+        interstitial: link_data.interstitial,
+        tags,
+    };
+
+    match link.update(changes, &conn).await {
+        Ok(link) => {
+            cache.invalidate(&link);
+
+            let response = match LinkResponse::build(link, &conn).await {
+                Ok(response) => response,
+                Err(e) => return APIResult::internal_server_error(e.to_string()),
+            };
+
+            webhook::enqueue(Event::Updated, &response, &conn).await;
+
+            APIResult::ok(response)
+        }
         Err(error) => APIResult::unprocessable_entity(error),
     }
 }
 
 #[delete("/<id>", format = "application/json")]
-async fn delete(id: i32, conn: DbConn, _api_key: APIKey) -> APIResult {
+async fn delete(
+    id: i32,
+    conn: DbConn,
+    cache: &State<LinkCache>,
+    _api_key: APIKey,
+) -> APIResult {
     let link = match Link::find(id, &conn).await {
         Ok(link) => link,
         Err(_) => return APIResult::not_found("Link not found".to_string()),
     };
 
-    if link.delete(&conn).await {
+    let response = match LinkResponse::build(link.clone(), &conn).await {
+        Ok(response) => response,
+        Err(e) => return APIResult::internal_server_error(e.to_string()),
+    };
+
+    if link.clone().delete(&conn).await {
+        cache.invalidate(&link);
+        webhook::enqueue(Event::Deleted, &response, &conn).await;
+
         APIResult::no_content()
     } else {
         APIResult::internal_server_error("Failed to delete link".to_string())
     }
 }
 
-#[get("/<hash>")]
-async fn redirect(hash: String, conn: DbConn) -> Result<Redirect, Status> {
-    let link = match Link::find_by_hash(hash.to_lowercase(), &conn).await {
+#[post("/<id>/restore", format = "application/json")]
+async fn restore(
+    id: i32,
+    conn: DbConn,
+    cache: &State<LinkCache>,
+    _api_key: APIKey,
+) -> APIResult {
+    let link = match Link::find_deleted(id, &conn).await {
         Ok(link) => link,
-        Err(_) => return Err(Status::NotFound),
+        Err(_) => return APIResult::not_found("Link not found".to_string()),
     };
 
-    let url = link.url.clone();
+    match link.restore(&conn).await {
+        Ok(link) => {
+            cache.invalidate(&link);
+
+            match LinkResponse::build(link, &conn).await {
+                Ok(response) => APIResult::ok(response),
+                Err(e) => APIResult::internal_server_error(e.to_string()),
+            }
+        }
+        Err(error) => APIResult::internal_server_error(error),
+    }
+}
+
+#[post("/<id>/disable", data = "<disable_data>", format = "application/json")]
+async fn disable(
+    id: i32,
+    disable_data: Json<DisableRequest>,
+    conn: DbConn,
+    cache: &State<LinkCache>,
+    _api_key: APIKey,
+) -> APIResult {
+    let link = match Link::find(id, &conn).await {
+        Ok(link) => link,
+        Err(_) => return APIResult::not_found("Link not found".to_string()),
+    };
+
+    let reason = disable_data
+        .into_inner()
+        .reason
+        .unwrap_or_else(|| "Disabled by an administrator".to_string());
+
+    match link.disable(reason, &conn).await {
+        Ok(link) => {
+            cache.invalidate(&link);
+
+            match LinkResponse::build(link, &conn).await {
+                Ok(response) => APIResult::ok(response),
+                Err(e) => APIResult::internal_server_error(e.to_string()),
+            }
+        }
+        Err(error) => APIResult::internal_server_error(error),
+    }
+}
+
+#[post("/<id>/enable", format = "application/json")]
+async fn enable(
+    id: i32,
+    conn: DbConn,
+    cache: &State<LinkCache>,
+    _api_key: APIKey,
+) -> APIResult {
+    let link = match Link::find(id, &conn).await {
+        Ok(link) => link,
+        Err(_) => return APIResult::not_found("Link not found".to_string()),
+    };
+
+    match link.enable(&conn).await {
+        Ok(link) => {
+            cache.invalidate(&link);
+
+            match LinkResponse::build(link, &conn).await {
+                Ok(response) => APIResult::ok(response),
+                Err(e) => APIResult::internal_server_error(e.to_string()),
+            }
+        }
+        Err(error) => APIResult::internal_server_error(error),
+    }
+}
+
+// `/<hash>+` or `/<hash>?preview` shows where a link goes instead of going there,
+// `?go` skips the preview for links that always show one.
+// `?qr` marks visits from QR codes, it's only used for counting and never reaches the destination.
+#[get("/<hash>?<preview>&<go>&<qr>")]
+#[allow(clippy::too_many_arguments)]
+async fn redirect(
+    hash: String,
+    preview: Option<String>,
+    go: Option<String>,
+    qr: Option<String>,
+    domain: RequestDomain,
+    user_agent: UserAgent,
+    client_ip: ClientIp,
+    referrer: Referrer,
+    visits: &State<VisitRecorder>,
+    sinks: &State<ClickSinks>,
+    cache: &State<LinkCache>,
+    pool: Pool<'_>,
+) -> Result<RedirectResult, Status> {
+    let domain_id = domain.0.map(|domain| domain.id);
+    let channel = if qr.is_some() { Channel::Qr } else { Channel::Direct };
+    let (hash, preview) = match hash.strip_suffix('+') {
+        Some(hash) => (hash.to_string(), true),
+        None => (hash, preview.is_some()),
+    };
+
+    // Cache hits don't wait on the pool, only misses take a connection
+    let link = match cache.find_by_hash(hash, domain_id, pool.0).await {
+        Ok(Some(link)) => link,
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            log::error!("Failed to look up hash: {}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    if link.is_disabled() {
+        return Ok(RedirectResult::Disabled(Template::render(
+            "disabled",
+            context! {
+                code: 410,
+                page_title: "Link Disabled",
+            },
+        )));
+    }
 
-    if link.increment_visitors(&conn).await {
-        Ok(Redirect::to(url))
+    // Unfurls aren't visits, and they should preview the link rather than wherever it ends up
+    if user_agent.is_unfurler() {
+        let short_url = link.redirect_url();
+        let title = match &link.title {
+            Some(title) if !title.is_empty() => title.clone(),
+            _ => link.url.clone(),
+        };
+
+        return Ok(RedirectResult::Preview(Template::render(
+            "social_preview",
+            context! {
+                title,
+                description: link.description,
+                image_url: link.image_url,
+                url: link.url,
+                short_url,
+            },
+        )));
+    }
+
+    if preview || (link.interstitial && go.is_none()) {
+        return Ok(RedirectResult::Interstitial(Template::render(
+            "preview",
+            context! {
+                code: "",
+                page_title: "Link Preview",
+                url: &link.url,
+                title: &link.title,
+                created_at: link.created_at.format("%B %-d, %Y").to_string(),
+                visitors: link.visitors,
+                continue_url: match channel {
+                    Channel::Qr => format!("/{}?go&qr", link.hash),
+                    Channel::Direct => format!("/{}?go", link.hash),
+                },
+            },
+        )));
+    }
+
+    let is_bot = user_agent.is_bot();
+
+    sinks.emit(ClickEvent {
+        link_id: link.id,
+        hash: link.hash.clone(),
+        url: link.url.clone(),
+        channel: channel.as_str().to_string(),
+        is_bot,
+        referrer: referrer.0.clone(),
+        user_agent: user_agent.0.clone(),
+        created_at: chrono::Utc::now().naive_utc(),
+    });
+
+    visits.record(NewVisit {
+        link_id: link.id,
+        channel,
+        is_bot,
+        ip: client_ip.0,
+        user_agent: user_agent.0,
+        referrer: referrer.0,
+    });
+
+    Ok(RedirectResult::Redirect(Redirect::to(link.url)))
+}
+
+#[get("/", format = "application/json")]
+async fn domain_index(conn: DbConn, _api_key: APIKey) -> Result<Json<Vec<Domain>>, Status> {
+    match Domain::all(&conn).await {
+        Ok(domains) => Ok(Json(domains)),
+        Err(e) => {
+            log::error!("Failed to load domains: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[post("/", data = "<domain_data>", format = "application/json")]
+async fn domain_new(
+    domain_data: Json<DomainRequest>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<(Status, Json<Domain>), APIResult> {
+    match Domain::insert(domain_data.into_inner().host, &conn).await {
+        Ok(domain) => Ok((Status::Created, Json(domain))),
+        Err(error) => Err(APIResult::unprocessable_entity(error)),
+    }
+}
+
+#[delete("/<id>", format = "application/json")]
+async fn domain_delete(id: i32, conn: DbConn, _api_key: APIKey) -> APIResult {
+    let domain = match Domain::find(id, &conn).await {
+        Ok(domain) => domain,
+        Err(error) => return APIResult::not_found(error),
+    };
+
+    match domain.delete(&conn).await {
+        Ok(()) => APIResult::no_content(),
+        Err(error) => APIResult::conflict(error),
+    }
+}
+
+#[get("/", format = "application/json")]
+async fn campaign_index(
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<Vec<CampaignResponse>>, Status> {
+    match Campaign::all_with_totals(&conn).await {
+        Ok(campaigns) => Ok(Json(
+            campaigns
+                .into_iter()
+                .map(|(campaign, totals)| CampaignResponse::new(campaign, totals))
+                .collect(),
+        )),
+        Err(e) => {
+            log::error!("Failed to load campaigns: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/<id>", format = "application/json")]
+async fn campaign_show(
+    id: i32,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<CampaignResponse>, APIResult> {
+    let campaign = match Campaign::find(id, &conn).await {
+        Ok(campaign) => campaign,
+        Err(error) => return Err(APIResult::not_found(error)),
+    };
+
+    match campaign.totals(&conn).await {
+        Ok(totals) => Ok(Json(CampaignResponse::new(campaign, totals))),
+        Err(e) => Err(APIResult::internal_server_error(e.to_string())),
+    }
+}
+
+#[post("/", data = "<campaign_data>", format = "application/json")]
+async fn campaign_new(
+    campaign_data: Json<CampaignRequest>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<(Status, Json<CampaignResponse>), APIResult> {
+    let campaign_data = campaign_data.into_inner();
+
+    match Campaign::insert(campaign_data.name, campaign_data.description, &conn).await {
+        Ok(campaign) => Ok((Status::Created, Json(CampaignResponse::new(campaign, (0, 0, 0))))),
+        Err(error) => Err(APIResult::unprocessable_entity(error)),
+    }
+}
+
+#[put("/<id>", data = "<campaign_data>", format = "application/json")]
+async fn campaign_update(
+    id: i32,
+    campaign_data: Json<CampaignUpdateRequest>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<CampaignResponse>, APIResult> {
+    let campaign = match Campaign::find(id, &conn).await {
+        Ok(campaign) => campaign,
+        Err(error) => return Err(APIResult::not_found(error)),
+    };
+
+    let campaign_data = campaign_data.into_inner();
+    let campaign = match campaign
+        .update(campaign_data.name, campaign_data.description, &conn)
+        .await
+    {
+        Ok(campaign) => campaign,
+        Err(error) => return Err(APIResult::unprocessable_entity(error)),
+    };
+
+    match campaign.totals(&conn).await {
+        Ok(totals) => Ok(Json(CampaignResponse::new(campaign, totals))),
+        Err(e) => Err(APIResult::internal_server_error(e.to_string())),
+    }
+}
+
+#[delete("/<id>", format = "application/json")]
+async fn campaign_delete(id: i32, conn: DbConn, _api_key: APIKey) -> APIResult {
+    let campaign = match Campaign::find(id, &conn).await {
+        Ok(campaign) => campaign,
+        Err(error) => return APIResult::not_found(error),
+    };
+
+    if campaign.delete(&conn).await {
+        APIResult::no_content()
     } else {
-        Err(Status::InternalServerError)
+        APIResult::internal_server_error("Failed to delete campaign".to_string())
+    }
+}
+
+#[get("/", format = "application/json")]
+async fn tag_index(conn: DbConn, _api_key: APIKey) -> Result<Json<Vec<TagCount>>, Status> {
+    match tag::counts(&conn).await {
+        Ok(tags) => Ok(Json(tags)),
+        Err(e) => {
+            log::error!("Failed to load tag counts: {}", e);
+            Err(Status::InternalServerError)
+        }
     }
 }
 
+#[get("/", format = "application/json")]
+async fn webhook_index(
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<Vec<WebhookResponse>>, Status> {
+    match Webhook::all(&conn).await {
+        Ok(webhooks) => Ok(Json(
+            webhooks
+                .into_iter()
+                .map(|webhook| WebhookResponse::new(webhook, false))
+                .collect(),
+        )),
+        Err(e) => {
+            log::error!("Failed to load webhooks: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/<id>", format = "application/json")]
+async fn webhook_show(
+    id: i32,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<WebhookResponse>, APIResult> {
+    match Webhook::find(id, &conn).await {
+        Ok(webhook) => Ok(Json(WebhookResponse::new(webhook, false))),
+        Err(error) => Err(APIResult::not_found(error)),
+    }
+}
+
+#[post("/", data = "<webhook_data>", format = "application/json")]
+async fn webhook_new(
+    webhook_data: Json<WebhookRequest>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<(Status, Json<WebhookResponse>), APIResult> {
+    let webhook_data = webhook_data.into_inner();
+
+    match Webhook::insert(
+        webhook_data.url,
+        webhook_data.events.unwrap_or_default(),
+        webhook_data.secret,
+        webhook_data.click_threshold,
+        &conn,
+    )
+    .await
+    {
+        Ok(webhook) => Ok((Status::Created, Json(WebhookResponse::new(webhook, true)))),
+        Err(error) => Err(APIResult::unprocessable_entity(error)),
+    }
+}
+
+#[put("/<id>", data = "<webhook_data>", format = "application/json")]
+async fn webhook_update(
+    id: i32,
+    webhook_data: Json<WebhookUpdateRequest>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<WebhookResponse>, APIResult> {
+    let webhook = match Webhook::find(id, &conn).await {
+        Ok(webhook) => webhook,
+        Err(error) => return Err(APIResult::not_found(error)),
+    };
+
+    let webhook_data = webhook_data.into_inner();
+    let secret_changed = webhook_data.secret.is_some();
+    let changes = WebhookChanges {
+        url: webhook_data.url,
+        events: webhook_data.events,
+        secret: webhook_data.secret,
+        click_threshold: webhook_data.click_threshold,
+    };
+
+    match webhook.update(changes, &conn).await {
+        Ok(webhook) => Ok(Json(WebhookResponse::new(webhook, secret_changed))),
+        Err(error) => Err(APIResult::unprocessable_entity(error)),
+    }
+}
+
+#[delete("/<id>", format = "application/json")]
+async fn webhook_delete(id: i32, conn: DbConn, _api_key: APIKey) -> APIResult {
+    let webhook = match Webhook::find(id, &conn).await {
+        Ok(webhook) => webhook,
+        Err(error) => return APIResult::not_found(error),
+    };
+
+    if webhook.delete(&conn).await {
+        APIResult::no_content()
+    } else {
+        APIResult::internal_server_error("Failed to delete webhook".to_string())
+    }
+}
+
+// The most recent delivery attempts, newest first
+#[get("/<id>/deliveries?<limit>", format = "application/json")]
+async fn webhook_deliveries(
+    id: i32,
+    limit: Option<i64>,
+    conn: DbConn,
+    _api_key: APIKey,
+) -> Result<Json<Vec<Delivery>>, APIResult> {
+    let webhook = match Webhook::find(id, &conn).await {
+        Ok(webhook) => webhook,
+        Err(error) => return Err(APIResult::not_found(error)),
+    };
+
+    match webhook.deliveries(limit.unwrap_or(50).clamp(1, 500), &conn).await {
+        Ok(deliveries) => Ok(Json(deliveries)),
+        Err(e) => Err(APIResult::internal_server_error(e.to_string())),
+    }
+}
+
+#[get("/", format = "application/json")]
+fn cache_stats(cache: &State<LinkCache>, _api_key: APIKey) -> Json<CacheStats> {
+    Json(cache.stats())
+}
+
+#[get("/", format = "application/json")]
+fn sink_stats(sinks: &State<ClickSinks>, _api_key: APIKey) -> Json<SinkStats> {
+    Json(sinks.stats())
+}
+
 // Intentionally empty, but required for preflight
 #[options("/<_..>")]
 fn options_all() -> Status {
@@ -202,14 +976,31 @@ fn rocket() -> _ {
         .merge(("databases", map![db_name => map!("pool_size" => 5)]));
 
     rocket::custom(figment)
+        .manage(LinkCache::from_env())
+        .manage(TrustedProxies::from_env())
         .attach(Cors)
         .attach(Template::fairing())
         .attach(DbConn::fairing())
+        .attach(UrlCheckers::fairing())
+        .attach(retention::fairing())
+        .attach(canonical::backfill_fairing())
+        .attach(AliasRules::fairing())
+        .attach(Domain::fairing())
+        .attach(MetadataFetcher::fairing())
+        .attach(VisitRecorder::fairing())
+        .attach(webhook::fairing())
+        .attach(ClickSinks::fairing(database_url.to_string()))
         .attach(AdHoc::on_ignite("Run Migrations", run_migrations))
         .mount("/", routes![redirect, options_all])
         .register("/", catchers![not_found, internal_server_error_redirect])
         .mount("/public", FileServer::from("public"))
-        .mount("/api/links", routes![index, show, new, delete])
+        .mount(
+            "/api/links",
+            routes![
+                index, show, breakdown, referrers, qr_svg, qr_png, new, update, delete, restore,
+                disable, enable
+            ],
+        )
         .register(
             "/api/links",
             catchers![
@@ -219,4 +1010,59 @@ fn rocket() -> _ {
                 unauthorized
             ],
         )
+        .mount(
+            "/api/campaigns",
+            routes![
+                campaign_index,
+                campaign_show,
+                campaign_new,
+                campaign_update,
+                campaign_delete
+            ],
+        )
+        .register(
+            "/api/campaigns",
+            catchers![
+                unprocessable_entity,
+                bad_request,
+                internal_server_error,
+                unauthorized
+            ],
+        )
+        .mount("/api/tags", routes![tag_index])
+        .register("/api/tags", catchers![internal_server_error, unauthorized])
+        .mount(
+            "/api/webhooks",
+            routes![
+                webhook_index,
+                webhook_show,
+                webhook_new,
+                webhook_update,
+                webhook_delete,
+                webhook_deliveries
+            ],
+        )
+        .register(
+            "/api/webhooks",
+            catchers![
+                unprocessable_entity,
+                bad_request,
+                internal_server_error,
+                unauthorized
+            ],
+        )
+        .mount("/api/cache", routes![cache_stats])
+        .register("/api/cache", catchers![unauthorized])
+        .mount("/api/sinks", routes![sink_stats])
+        .register("/api/sinks", catchers![unauthorized])
+        .mount("/api/domains", routes![domain_index, domain_new, domain_delete])
+        .register(
+            "/api/domains",
+            catchers![
+                unprocessable_entity,
+                bad_request,
+                internal_server_error,
+                unauthorized
+            ],
+        )
 }
@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rocket::fairing::AdHoc;
+use rocket::tokio::{self, sync::mpsc};
+use scraper::{Html, Selector};
+use url::Url;
+
+use crate::{cache::LinkCache, http, link::Link, policy, DbConn};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Default, Debug, PartialEq)]
+pub struct PageMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_url: Option<String>,
+    pub favicon_url: Option<String>,
+}
+
+// Fetches metadata for new links in the background, so creating a link
+// never waits on the destination
+pub struct MetadataFetcher {
+    sender: mpsc::UnboundedSender<i32>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<i32>>>,
+}
+
+impl MetadataFetcher {
+    pub fn enqueue(&self, link_id: i32) {
+        if self.sender.send(link_id).is_err() {
+            log::warn!("Metadata worker isn't running, skipping link {}", link_id);
+        }
+    }
+
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_ignite("Metadata Fetcher", |rocket| async {
+            let (sender, receiver) = mpsc::unbounded_channel();
+
+            rocket
+                .manage(MetadataFetcher {
+                    sender,
+                    receiver: Mutex::new(Some(receiver)),
+                })
+                .attach(AdHoc::on_liftoff("Metadata Worker", |rocket| {
+                    Box::pin(async move {
+                        let receiver = rocket
+                            .state::<MetadataFetcher>()
+                            .and_then(|fetcher| fetcher.receiver.lock().take());
+                        let cache = rocket.state::<LinkCache>().cloned();
+                        let pool = DbConn::pool(rocket).cloned();
+
+                        if let (Some(receiver), Some(cache), Some(pool)) = (receiver, cache, pool) {
+                            tokio::spawn(run_worker(receiver, cache, pool));
+                        }
+                    })
+                }))
+        })
+    }
+}
+
+async fn run_worker(
+    mut receiver: mpsc::UnboundedReceiver<i32>,
+    cache: LinkCache,
+    pool: crate::DbPool,
+) {
+    let client = http::fetch_client(timeout(), policy::allow_private_hosts());
+
+    // Connections go back to the pool while the page is fetched
+    while let Some(link_id) = receiver.recv().await {
+        let link = match DbConn::from_pool(&pool).await {
+            Some(conn) => match Link::find(link_id, &conn).await {
+                Ok(link) => link,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+
+        let metadata = match fetch(&client, &link.url).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                log::error!("Failed to fetch metadata for link {}: {}", link_id, e);
+                continue;
+            }
+        };
+
+        let conn = match DbConn::from_pool(&pool).await {
+            Some(conn) => conn,
+            None => continue,
+        };
+
+        match link.apply_metadata(metadata, &conn).await {
+            Ok(Some(link)) => cache.invalidate(&link),
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to save metadata for link {}: {}", link_id, e),
+        }
+    }
+}
+
+// METADATA_FETCH_TIMEOUT is in seconds
+fn timeout() -> Duration {
+    std::env::var("METADATA_FETCH_TIMEOUT")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+// Only reads the first MAX_BODY_SIZE bytes of HTML pages
+pub async fn fetch(client: &reqwest::Client, url: &str) -> Result<PageMetadata, String> {
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(http::describe)?;
+
+    let is_html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map_or(false, |content_type| content_type.contains("text/html"));
+
+    if !is_html {
+        return Err("Destination isn't an HTML page".to_string());
+    }
+
+    let base = response.url().clone();
+    let mut body = vec![];
+
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        body.extend_from_slice(&chunk);
+
+        if body.len() >= MAX_BODY_SIZE {
+            body.truncate(MAX_BODY_SIZE);
+            break;
+        }
+    }
+
+    Ok(parse(&String::from_utf8_lossy(&body), &base))
+}
+
+pub fn parse(html: &str, base: &Url) -> PageMetadata {
+    let document = Html::parse_document(html);
+
+    let title = meta_content(&document, "meta[property='og:title']")
+        .or_else(|| meta_content(&document, "meta[name='twitter:title']"))
+        .or_else(|| element_text(&document, "title"));
+    let description = meta_content(&document, "meta[property='og:description']")
+        .or_else(|| meta_content(&document, "meta[name='description']"));
+    let image_url = meta_content(&document, "meta[property='og:image']")
+        .or_else(|| meta_content(&document, "meta[name='twitter:image']"))
+        .and_then(|image| absolute_url(base, &image));
+    let favicon_url = attribute(&document, "link[rel~='icon']", "href")
+        .and_then(|icon| absolute_url(base, &icon))
+        .or_else(|| absolute_url(base, "/favicon.ico"));
+
+    PageMetadata {
+        title,
+        description,
+        image_url,
+        favicon_url,
+    }
+}
+
+fn meta_content(document: &Html, selector: &str) -> Option<String> {
+    attribute(document, selector, "content")
+}
+
+fn attribute(document: &Html, selector: &str, attribute: &str) -> Option<String> {
+    let selector = Selector::parse(selector).ok()?;
+
+    document
+        .select(&selector)
+        .filter_map(|element| element.value().attr(attribute))
+        .map(|value| value.trim().to_string())
+        .find(|value| !value.is_empty())
+}
+
+fn element_text(document: &Html, selector: &str) -> Option<String> {
+    let selector = Selector::parse(selector).ok()?;
+    let element = document.select(&selector).next()?;
+    let text = element.text().collect::<Vec<_>>().join(" ");
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+fn absolute_url(base: &Url, url: &str) -> Option<String> {
+    base.join(url).ok().map(|url| url.to_string())
+}
@@ -0,0 +1,186 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use url::{Host, Url};
+
+use crate::domain::Domain;
+
+const DEFAULT_ALLOWED_SCHEMES: &[&str] = &["http", "https"];
+const DEFAULT_SHORTENER_HOSTS: &[&str] = &[
+    "bit.ly",
+    "buff.ly",
+    "cutt.ly",
+    "goo.gl",
+    "is.gd",
+    "ow.ly",
+    "rebrand.ly",
+    "t.co",
+    "tinyurl.com",
+];
+
+// Rules a destination URL has to pass before a link can point at it.
+//
+// Configured through the environment, host lists are comma separated and
+// accept wildcards such as `*.example.com`:
+//   ALLOWED_SCHEMES      defaults to "http,https"
+//   ALLOWED_HOSTS        when set, only these hosts may be linked to
+//   DENIED_HOSTS         hosts that may never be linked to
+//   SHORTENER_HOSTS      other shorteners, linking to them would chain short links
+//   ALLOW_PRIVATE_HOSTS  set to "true" to allow localhost and private IPs
+pub struct UrlPolicy {
+    pub allowed_schemes: Vec<String>,
+    pub allowed_hosts: Vec<String>,
+    pub denied_hosts: Vec<String>,
+    pub shortener_hosts: Vec<String>,
+    pub allow_private_hosts: bool,
+    pub own_hosts: Vec<String>,
+}
+
+impl UrlPolicy {
+    pub fn from_env() -> Self {
+        UrlPolicy {
+            allowed_schemes: env_list("ALLOWED_SCHEMES")
+                .unwrap_or_else(|| to_strings(DEFAULT_ALLOWED_SCHEMES)),
+            allowed_hosts: env_list("ALLOWED_HOSTS").unwrap_or_default(),
+            denied_hosts: env_list("DENIED_HOSTS").unwrap_or_default(),
+            shortener_hosts: env_list("SHORTENER_HOSTS")
+                .unwrap_or_else(|| to_strings(DEFAULT_SHORTENER_HOSTS)),
+            allow_private_hosts: allow_private_hosts(),
+            own_hosts: std::env::var("WHO_AM_I")
+                .ok()
+                .and_then(|who_am_i| host_of(&who_am_i))
+                .into_iter()
+                .chain(Domain::hosts())
+                .collect(),
+        }
+    }
+
+    pub fn check(&self, url: &Url) -> Vec<String> {
+        let mut errors = vec![];
+
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|scheme| scheme == url.scheme())
+        {
+            errors.push(format!("URL scheme '{}' is not allowed", url.scheme()));
+            return errors;
+        }
+
+        let host = match url.host() {
+            Some(host) => host,
+            None => {
+                errors.push("URL must have a host".to_string());
+                return errors;
+            }
+        };
+        let host_name = host.to_string().to_lowercase();
+
+        if !self.allow_private_hosts && is_private_host(&host) {
+            errors.push("URL cannot point to a private or local address".to_string());
+        }
+
+        if self.own_hosts.contains(&host_name) {
+            errors.push("URL cannot point to this shortener".to_string());
+        }
+
+        if matches_any(&host_name, &self.shortener_hosts) {
+            errors.push("URL cannot point to another short link".to_string());
+        }
+
+        if matches_any(&host_name, &self.denied_hosts) {
+            errors.push(format!("Host '{}' is not allowed", host_name));
+        } else if !self.allowed_hosts.is_empty() && !matches_any(&host_name, &self.allowed_hosts) {
+            errors.push(format!("Host '{}' is not allowed", host_name));
+        }
+
+        errors
+    }
+}
+
+// `*.example.com` matches example.com and any of its subdomains
+fn host_matches(host: &str, pattern: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+        None => host == pattern,
+    }
+}
+
+fn matches_any(host: &str, patterns: &[String]) -> bool {
+    patterns.iter().any(|pattern| host_matches(host, pattern))
+}
+
+pub fn allow_private_hosts() -> bool {
+    std::env::var("ALLOW_PRIVATE_HOSTS").map_or(false, |value| value == "true")
+}
+
+pub fn is_private_url(url: &Url) -> bool {
+    url.host().map_or(false, |host| is_private_host(&host))
+}
+
+pub fn is_private_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => is_private_ipv6(ip),
+    }
+}
+
+fn is_private_host(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(domain) => {
+            let domain = domain.to_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+        Host::Ipv4(ip) => is_private_ipv4(ip),
+        Host::Ipv6(ip) => is_private_ipv6(ip),
+    }
+}
+
+fn is_private_ipv4(ip: &Ipv4Addr) -> bool {
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // 100.64.0.0/10, carrier-grade NAT
+        || (ip.octets()[0] == 100 && (ip.octets()[1] & 0b1100_0000) == 64)
+}
+
+fn is_private_ipv6(ip: &Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_private_ipv4(&ipv4);
+    }
+
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // fc00::/7, unique local
+        || (ip.segments()[0] & 0xfe00) == 0xfc00
+        // fe80::/10, link local
+        || (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+// WHO_AM_I may or may not include a scheme, e.g. "localhost:8000" or "https://kick.st"
+pub fn host_of(address: &str) -> Option<String> {
+    let with_scheme = if address.contains("://") {
+        address.to_string()
+    } else {
+        format!("http://{}", address)
+    };
+
+    Url::parse(&with_scheme)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+}
+
+fn env_list(name: &str) -> Option<Vec<String>> {
+    std::env::var(name).ok().map(|value| {
+        value
+            .split(',')
+            .map(|item| item.trim().to_lowercase())
+            .filter(|item| !item.is_empty())
+            .collect()
+    })
+}
+
+fn to_strings(items: &[&str]) -> Vec<String> {
+    items.iter().map(|item| item.to_string()).collect()
+}
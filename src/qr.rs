@@ -0,0 +1,178 @@
+use std::io::Cursor;
+
+use image::{ImageOutputFormat, Rgb, RgbImage};
+use qrcode::{Color, EcLevel, QrCode};
+
+const DEFAULT_SIZE: u32 = 300;
+const MAX_SIZE: u32 = 2000;
+// Margin is in modules, 4 is the quiet zone the QR spec asks for
+const DEFAULT_MARGIN: u32 = 4;
+const MAX_MARGIN: u32 = 20;
+
+#[derive(Debug, PartialEq)]
+pub struct QrOptions {
+    pub size: u32,
+    pub margin: u32,
+    pub ec_level: EcLevel,
+    pub foreground: [u8; 3],
+    pub background: [u8; 3],
+}
+
+impl Default for QrOptions {
+    fn default() -> Self {
+        QrOptions {
+            size: DEFAULT_SIZE,
+            margin: DEFAULT_MARGIN,
+            ec_level: EcLevel::M,
+            foreground: [0, 0, 0],
+            background: [255, 255, 255],
+        }
+    }
+}
+
+impl QrOptions {
+    pub fn from_params(
+        size: Option<u32>,
+        margin: Option<u32>,
+        ec_level: Option<&str>,
+        foreground: Option<&str>,
+        background: Option<&str>,
+    ) -> Result<Self, String> {
+        let mut options = QrOptions::default();
+
+        if let Some(size) = size {
+            if size == 0 || size > MAX_SIZE {
+                return Err(format!("Size must be between 1 and {}", MAX_SIZE));
+            }
+
+            options.size = size;
+        }
+
+        if let Some(margin) = margin {
+            if margin > MAX_MARGIN {
+                return Err(format!("Margin cannot be over {}", MAX_MARGIN));
+            }
+
+            options.margin = margin;
+        }
+
+        if let Some(ec_level) = ec_level {
+            options.ec_level = match ec_level.to_uppercase().as_str() {
+                "L" => EcLevel::L,
+                "M" => EcLevel::M,
+                "Q" => EcLevel::Q,
+                "H" => EcLevel::H,
+                _ => return Err("Error correction level must be one of L, M, Q or H".to_string()),
+            };
+        }
+
+        if let Some(foreground) = foreground {
+            options.foreground = parse_color(foreground)?;
+        }
+
+        if let Some(background) = background {
+            options.background = parse_color(background)?;
+        }
+
+        Ok(options)
+    }
+}
+
+// Accepts "rrggbb" with or without a leading '#'
+fn parse_color(color: &str) -> Result<[u8; 3], String> {
+    let hex = color.trim_start_matches('#');
+    let invalid = || format!("Invalid colour '{}', expected a hex colour like 000000", color);
+
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+
+    Ok([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn hex(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+struct Modules {
+    dark: Vec<bool>,
+    width: u32,
+}
+
+impl Modules {
+    fn encode(data: &str, ec_level: EcLevel) -> Result<Self, String> {
+        let code = QrCode::with_error_correction_level(data, ec_level).map_err(|e| e.to_string())?;
+
+        Ok(Modules {
+            width: code.width() as u32,
+            dark: code.to_colors().into_iter().map(|color| color == Color::Dark).collect(),
+        })
+    }
+
+    fn is_dark(&self, x: u32, y: u32) -> bool {
+        self.dark[(y * self.width + x) as usize]
+    }
+}
+
+pub fn svg(data: &str, options: &QrOptions) -> Result<String, String> {
+    let modules = Modules::encode(data, options.ec_level)?;
+    let dimension = modules.width + options.margin * 2;
+    let mut path = String::new();
+
+    for y in 0..modules.width {
+        for x in 0..modules.width {
+            if modules.is_dark(x, y) {
+                path.push_str(&format!(
+                    "M{},{}h1v1h-1z",
+                    x + options.margin,
+                    y + options.margin
+                ));
+            }
+        }
+    }
+
+    Ok(format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{size}" height="{size}" viewBox="0 0 {dimension} {dimension}" shape-rendering="crispEdges">"#,
+            r#"<rect width="{dimension}" height="{dimension}" fill="{background}"/>"#,
+            r#"<path d="{path}" fill="{foreground}"/>"#,
+            "</svg>"
+        ),
+        size = options.size,
+        dimension = dimension,
+        background = hex(options.background),
+        foreground = hex(options.foreground),
+        path = path,
+    ))
+}
+
+// Modules are always whole pixels so scanners don't struggle with blurry edges,
+// so the image can come out slightly smaller than the requested size
+pub fn png(data: &str, options: &QrOptions) -> Result<Vec<u8>, String> {
+    let modules = Modules::encode(data, options.ec_level)?;
+    let dimension = modules.width + options.margin * 2;
+    let scale = (options.size / dimension).max(1);
+
+    let image = RgbImage::from_fn(dimension * scale, dimension * scale, |x, y| {
+        let (x, y) = (x / scale, y / scale);
+        let inside = (options.margin..options.margin + modules.width).contains(&x)
+            && (options.margin..options.margin + modules.width).contains(&y);
+
+        if inside && modules.is_dark(x - options.margin, y - options.margin) {
+            Rgb(options.foreground)
+        } else {
+            Rgb(options.background)
+        }
+    });
+
+    let mut bytes = Cursor::new(vec![]);
+
+    image
+        .write_to(&mut bytes, ImageOutputFormat::Png)
+        .map_err(|e| e.to_string())?;
+
+    Ok(bytes.into_inner())
+}
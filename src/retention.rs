@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use rocket::fairing::AdHoc;
+use rocket::tokio;
+
+use crate::{link::Link, DbConn};
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+// Deleted links are kept for DELETED_LINK_RETENTION_DAYS so they can be restored,
+// after that they're removed for good and their hash is free again
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Purge Deleted Links", |rocket| {
+        Box::pin(async move {
+            let pool = match DbConn::pool(rocket) {
+                Some(pool) => pool.clone(),
+                None => return,
+            };
+            let retention = chrono::Duration::days(retention_days());
+
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(PURGE_INTERVAL).await;
+
+                    let conn = match DbConn::from_pool(&pool).await {
+                        Some(conn) => conn,
+                        None => continue,
+                    };
+                    let deleted_before = chrono::Utc::now().naive_utc() - retention;
+
+                    if let Err(e) = Link::purge_deleted(deleted_before, &conn).await {
+                        log::error!("Failed to purge deleted links: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
+
+fn retention_days() -> i64 {
+    std::env::var("DELETED_LINK_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS)
+}
@@ -0,0 +1,147 @@
+use diesel::{self, prelude::*, sql_types::BigInt};
+use rand::Rng;
+
+use crate::{alias, DbConn};
+
+// Hashes are looked up case-insensitively, so mixed case alphabets only add collisions
+pub const DEFAULT_ALPHABET: &str = "abcdefghijklmnopqrstuvwxyz0123456789";
+pub const DEFAULT_LENGTH: usize = 8;
+pub const MAX_LENGTH: usize = 50;
+
+// How many generated hashes to try before giving up on an insert
+pub const MAX_ATTEMPTS: usize = 5;
+
+// Any prime larger than the alphabet is coprime with every power of it,
+// which makes multiplying by it a bijection over a fixed-length code space
+const SEQUENCE_MULTIPLIER: u128 = 2_654_435_761;
+
+sql_function!(fn nextval(sequence: diesel::sql_types::Text) -> BigInt);
+
+#[rocket::async_trait]
+pub trait ShortCodeGenerator: Send + Sync {
+    async fn generate(&self, conn: &DbConn) -> Result<String, String>;
+}
+
+// Configured through the environment:
+//   SHORT_CODE_GENERATOR  "random" (default) or "sequence"
+//   SHORT_CODE_ALPHABET   characters codes are made of, lowercase letters, numbers, `-` and `_`
+//   SHORT_CODE_LENGTH     length of random codes, minimum length of sequence codes, up to 50
+pub fn from_env() -> Box<dyn ShortCodeGenerator> {
+    let alphabet = match std::env::var("SHORT_CODE_ALPHABET") {
+        Ok(alphabet) => parse_alphabet(&alphabet).unwrap_or_else(|e| {
+            log::error!("Ignoring SHORT_CODE_ALPHABET: {}", e);
+            default_alphabet()
+        }),
+        Err(_) => default_alphabet(),
+    };
+    let length = std::env::var("SHORT_CODE_LENGTH")
+        .ok()
+        .and_then(|length| length.parse::<usize>().ok())
+        .filter(|length| *length > 0 && *length <= MAX_LENGTH)
+        .unwrap_or(DEFAULT_LENGTH);
+
+    match std::env::var("SHORT_CODE_GENERATOR").as_deref() {
+        Ok("sequence") => Box::new(SequenceGenerator::new(alphabet, length)),
+        _ => Box::new(RandomGenerator::new(alphabet, length)),
+    }
+}
+
+// Codes have to be valid hashes, so uppercase letters and anything that isn't safe in a path
+// are rejected rather than producing codes that collide or can't be visited
+pub fn parse_alphabet(alphabet: &str) -> Result<Vec<char>, String> {
+    let mut chars: Vec<char> = vec![];
+
+    for char in alphabet.chars() {
+        if !alias::is_hash_char(char) {
+            return Err(format!("'{}' can't be used in short codes", char));
+        }
+
+        if !chars.contains(&char) {
+            chars.push(char);
+        }
+    }
+
+    if chars.len() < 2 {
+        return Err("An alphabet needs at least 2 characters".to_string());
+    }
+
+    Ok(chars)
+}
+
+fn default_alphabet() -> Vec<char> {
+    DEFAULT_ALPHABET.chars().collect()
+}
+
+pub struct RandomGenerator {
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+impl RandomGenerator {
+    pub fn new(alphabet: Vec<char>, length: usize) -> Self {
+        RandomGenerator { alphabet, length }
+    }
+}
+
+#[rocket::async_trait]
+impl ShortCodeGenerator for RandomGenerator {
+    async fn generate(&self, _conn: &DbConn) -> Result<String, String> {
+        let mut rng = rand::thread_rng();
+
+        Ok((0..self.length)
+            .map(|_| self.alphabet[rng.gen_range(0..self.alphabet.len())])
+            .collect())
+    }
+}
+
+// Encodes values from the `link_hash_seq` sequence, so codes never collide with each other.
+// Values are scrambled first so consecutive links don't get consecutive codes.
+pub struct SequenceGenerator {
+    alphabet: Vec<char>,
+    min_length: usize,
+}
+
+impl SequenceGenerator {
+    pub fn new(alphabet: Vec<char>, min_length: usize) -> Self {
+        SequenceGenerator {
+            alphabet,
+            min_length,
+        }
+    }
+
+    pub fn encode(&self, value: u64) -> String {
+        let base = self.alphabet.len() as u128;
+        let mut length = self.min_length as u32;
+
+        // A code space too big for u128 is bigger than any u64 value
+        while base.checked_pow(length).map_or(false, |space| space <= value as u128) {
+            length += 1;
+        }
+
+        // Any product fits in u128, so without the modulo it's still one code per value
+        let mut scrambled = match base.checked_pow(length) {
+            Some(space) => (value as u128 * SEQUENCE_MULTIPLIER) % space,
+            None => value as u128 * SEQUENCE_MULTIPLIER,
+        };
+        let mut code = vec![];
+
+        for _ in 0..length {
+            code.push(self.alphabet[(scrambled % base) as usize]);
+            scrambled /= base;
+        }
+
+        code.into_iter().rev().collect()
+    }
+}
+
+#[rocket::async_trait]
+impl ShortCodeGenerator for SequenceGenerator {
+    async fn generate(&self, conn: &DbConn) -> Result<String, String> {
+        let value = conn
+            .run(|c| diesel::select(nextval("link_hash_seq")).get_result::<i64>(c))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(self.encode(value as u64))
+    }
+}
@@ -0,0 +1,365 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::{pg::PgConnection, prelude::*, sql_types::Text};
+use parking_lot::{Mutex, RwLock};
+use rocket::fairing::AdHoc;
+use rocket::serde::json::serde_json;
+use rocket::tokio::{self, sync::mpsc, task::JoinHandle};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// Events past this wait in each sink's buffer are dropped, so a slow sink never
+// holds up redirects or other sinks
+const BUFFER_SIZE: usize = 10_000;
+const MAX_BATCH_SIZE: usize = 500;
+// Postgres refuses NOTIFY payloads of 8000 bytes or more
+const MAX_NOTIFY_PAYLOAD: usize = 7999;
+// How long shutdown waits for sinks to write what's left, a stuck one is given up on
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClickEvent {
+    pub link_id: i32,
+    pub hash: String,
+    pub url: String,
+    pub channel: String,
+    pub is_bot: bool,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+// Somewhere raw click events are streamed to. Writes happen in batches off the redirect path,
+// a failed batch is logged and dropped.
+#[rocket::async_trait]
+pub trait ClickSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn write(&self, events: &[ClickEvent]) -> Result<(), String>;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SinkStats {
+    pub sinks: usize,
+    // Events a sink's full buffer had no room for, since startup
+    pub dropped: u64,
+}
+
+// Each sink gets its own buffer and worker, emitting never waits on any of them
+pub struct ClickSinks {
+    // Emptied on shutdown, which closes the buffers
+    senders: RwLock<Vec<mpsc::Sender<ClickEvent>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    dropped: AtomicU64,
+}
+
+impl ClickSinks {
+    // Must be called from within the runtime, since it starts a worker per sink
+    pub fn spawn(sinks: Vec<Arc<dyn ClickSink>>, flush_interval: Duration) -> Self {
+        let (senders, workers): (Vec<_>, Vec<_>) = sinks
+            .into_iter()
+            .map(|sink| {
+                let (sender, receiver) = mpsc::channel(BUFFER_SIZE);
+                let worker = tokio::spawn(run_sink(sink, receiver, flush_interval));
+                (sender, worker)
+            })
+            .unzip();
+
+        ClickSinks {
+            senders: RwLock::new(senders),
+            workers: Mutex::new(workers),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn emit(&self, event: ClickEvent) {
+        for sender in self.senders.read().iter() {
+            if sender.try_send(event.clone()).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> SinkStats {
+        SinkStats {
+            sinks: self.senders.read().len(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    // Closes every buffer and waits for the workers to write what's left.
+    // Events emitted afterwards go nowhere.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.senders.write().clear();
+
+        let workers = std::mem::take(&mut *self.workers.lock());
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        for worker in workers {
+            match tokio::time::timeout_at(deadline, worker).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => log::error!("Click sink worker failed: {}", e),
+                Err(_) => {
+                    log::error!("Gave up waiting for click sinks to flush");
+                    return;
+                }
+            }
+        }
+    }
+
+    // CLICK_SINK_FILE is a path, or `-` for stdout. CLICK_SINK_URL receives POSTed JSON arrays.
+    // CLICK_SINK_NOTIFY_CHANNEL is a Postgres channel to NOTIFY with each event.
+    // CLICK_SINK_FLUSH_INTERVAL is in seconds.
+    pub fn fairing(database_url: String) -> AdHoc {
+        AdHoc::on_ignite("Click Sinks", |rocket| async move {
+            let mut sinks: Vec<Arc<dyn ClickSink>> = vec![];
+
+            match std::env::var("CLICK_SINK_FILE").ok().as_deref() {
+                Some("-") => sinks.push(Arc::new(JsonLinesSink::stdout())),
+                Some(path) => match JsonLinesSink::file(path) {
+                    Ok(sink) => sinks.push(Arc::new(sink)),
+                    Err(e) => log::error!("Failed to open click sink file {}: {}", path, e),
+                },
+                None => {}
+            }
+
+            if let Ok(url) = std::env::var("CLICK_SINK_URL") {
+                sinks.push(Arc::new(HttpBatchSink::new(url)));
+            }
+
+            if let Ok(channel) = std::env::var("CLICK_SINK_NOTIFY_CHANNEL") {
+                sinks.push(Arc::new(PgNotifySink::new(channel, database_url)));
+            }
+
+            let flush_interval = std::env::var("CLICK_SINK_FLUSH_INTERVAL")
+                .ok()
+                .and_then(|seconds| seconds.parse::<u64>().ok())
+                .filter(|seconds| *seconds > 0)
+                .map_or(DEFAULT_FLUSH_INTERVAL, Duration::from_secs);
+
+            rocket
+                .manage(ClickSinks::spawn(sinks, flush_interval))
+                .attach(AdHoc::on_shutdown("Flush Click Sinks", |rocket| {
+                    Box::pin(async move {
+                        if let Some(sinks) = rocket.state::<ClickSinks>() {
+                            sinks.shutdown(SHUTDOWN_TIMEOUT).await;
+                        }
+                    })
+                }))
+        })
+    }
+}
+
+async fn run_sink(
+    sink: Arc<dyn ClickSink>,
+    mut receiver: mpsc::Receiver<ClickEvent>,
+    flush_interval: Duration,
+) {
+    let mut batch = vec![];
+    let mut ticker = tokio::time::interval(flush_interval);
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Some(event) => {
+                    batch.push(event);
+
+                    if batch.len() >= MAX_BATCH_SIZE {
+                        write_batch(sink.as_ref(), &mut batch).await;
+                    }
+                }
+                None => {
+                    write_batch(sink.as_ref(), &mut batch).await;
+                    return;
+                }
+            },
+            _ = ticker.tick() => write_batch(sink.as_ref(), &mut batch).await,
+        }
+    }
+}
+
+async fn write_batch(sink: &dyn ClickSink, batch: &mut Vec<ClickEvent>) {
+    if batch.is_empty() {
+        return;
+    }
+
+    if let Err(e) = sink.write(batch).await {
+        log::error!(
+            "Click sink {} failed, dropped {} events: {}",
+            sink.name(),
+            batch.len(),
+            e
+        );
+    }
+
+    batch.clear();
+}
+
+// One JSON object per line
+pub struct JsonLinesSink {
+    output: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl JsonLinesSink {
+    pub fn stdout() -> Self {
+        JsonLinesSink {
+            output: Arc::new(Mutex::new(Box::new(std::io::stdout()))),
+        }
+    }
+
+    // Appends, so restarts don't lose earlier events
+    pub fn file(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(JsonLinesSink {
+            output: Arc::new(Mutex::new(Box::new(file))),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl ClickSink for JsonLinesSink {
+    fn name(&self) -> &'static str {
+        "json-lines"
+    }
+
+    async fn write(&self, events: &[ClickEvent]) -> Result<(), String> {
+        let mut lines = String::new();
+
+        for event in events {
+            lines.push_str(&serde_json::to_string(event).map_err(|e| e.to_string())?);
+            lines.push('\n');
+        }
+
+        let output = self.output.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut output = output.lock();
+            output.write_all(lines.as_bytes())?;
+            output.flush()
+        })
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+    }
+}
+
+// POSTs each batch as a JSON array
+pub struct HttpBatchSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpBatchSink {
+    pub fn new(url: String) -> Self {
+        HttpBatchSink {
+            client: crate::http::client(HTTP_TIMEOUT),
+            url,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl ClickSink for HttpBatchSink {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn write(&self, events: &[ClickEvent]) -> Result<(), String> {
+        self.client
+            .post(&self.url)
+            .json(events)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+// NOTIFYs the channel with each event as JSON, a batch at a time in one transaction.
+// Events too big for a NOTIFY are skipped. Uses its own connection rather than one from
+// the pool, and reconnects after errors.
+pub struct PgNotifySink {
+    channel: String,
+    database_url: String,
+    connection: Arc<Mutex<Option<PgConnection>>>,
+}
+
+impl PgNotifySink {
+    pub fn new(channel: String, database_url: String) -> Self {
+        PgNotifySink {
+            channel,
+            database_url,
+            connection: Arc::default(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl ClickSink for PgNotifySink {
+    fn name(&self) -> &'static str {
+        "postgres-notify"
+    }
+
+    async fn write(&self, events: &[ClickEvent]) -> Result<(), String> {
+        let mut payloads = vec![];
+
+        for event in events {
+            let payload = serde_json::to_string(event).map_err(|e| e.to_string())?;
+
+            if payload.len() > MAX_NOTIFY_PAYLOAD {
+                log::warn!(
+                    "Click sink {} skipped a {} byte event for link {}",
+                    self.name(),
+                    payload.len(),
+                    event.link_id
+                );
+                continue;
+            }
+
+            payloads.push(payload);
+        }
+
+        if payloads.is_empty() {
+            return Ok(());
+        }
+
+        let channel = self.channel.clone();
+        let database_url = self.database_url.clone();
+        let connection = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock();
+
+            // Only put back once it's worked, so the next batch reconnects after an error
+            let c = match connection.take() {
+                Some(c) => c,
+                None => PgConnection::establish(&database_url).map_err(|e| e.to_string())?,
+            };
+
+            c.transaction::<_, diesel::result::Error, _>(|| {
+                for payload in payloads {
+                    diesel::sql_query("SELECT pg_notify($1, $2)")
+                        .bind::<Text, _>(&channel)
+                        .bind::<Text, _>(payload)
+                        .execute(&c)?;
+                }
+
+                Ok(())
+            })
+            .map_err(|e| e.to_string())?;
+
+            *connection = Some(c);
+
+            Ok(())
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+}
@@ -0,0 +1,111 @@
+use diesel::{
+    self,
+    pg::PgConnection,
+    prelude::*,
+    sql_types::{BigInt, Varchar},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    link::schema::{link_tags, tags},
+    DbConn,
+};
+
+const MAX_TAG_LENGTH: usize = 50;
+
+#[derive(QueryableByName, Serialize, Deserialize, Debug)]
+pub struct TagCount {
+    #[sql_type = "Varchar"]
+    pub name: String,
+    #[sql_type = "BigInt"]
+    pub links: i64,
+}
+
+// Tags are free-form, but stored trimmed and lowercased so "Email" and "email" are the same tag
+pub fn normalize(names: &[String]) -> Result<Vec<String>, String> {
+    let mut tags: Vec<String> = vec![];
+
+    for name in names {
+        let name = name.trim().to_lowercase();
+
+        if name.is_empty() {
+            return Err("Tags cannot be empty".to_string());
+        }
+
+        if name.len() > MAX_TAG_LENGTH {
+            return Err(format!("Tags cannot be over {} characters", MAX_TAG_LENGTH));
+        }
+
+        if !tags.contains(&name) {
+            tags.push(name);
+        }
+    }
+
+    Ok(tags)
+}
+
+pub async fn for_link(link_id: i32, conn: &DbConn) -> QueryResult<Vec<String>> {
+    conn.run(move |c| {
+        link_tags::table
+            .inner_join(tags::table)
+            .filter(link_tags::link_id.eq(link_id))
+            .order(tags::name.asc())
+            .select(tags::name)
+            .load::<String>(c)
+    })
+    .await
+}
+
+// Replaces every tag on the link, `names` should already be normalized.
+// Meant to run in the same transaction as the link's own insert or update.
+pub fn replace(link_id: i32, names: &[String], c: &PgConnection) -> QueryResult<()> {
+    diesel::delete(link_tags::table.filter(link_tags::link_id.eq(link_id))).execute(c)?;
+
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let new_tags: Vec<_> = names.iter().map(|name| tags::name.eq(name)).collect();
+
+    diesel::insert_into(tags::table)
+        .values(&new_tags)
+        .on_conflict(tags::name)
+        .do_nothing()
+        .execute(c)?;
+
+    let tag_ids = tags::table
+        .filter(tags::name.eq_any(names))
+        .select(tags::id)
+        .load::<i32>(c)?;
+
+    let new_link_tags: Vec<_> = tag_ids
+        .into_iter()
+        .map(|tag_id| (link_tags::link_id.eq(link_id), link_tags::tag_id.eq(tag_id)))
+        .collect();
+
+    diesel::insert_into(link_tags::table)
+        .values(&new_link_tags)
+        .execute(c)?;
+
+    Ok(())
+}
+
+// Every tag with the number of links using it, deleted links don't count
+pub async fn counts(conn: &DbConn) -> QueryResult<Vec<TagCount>> {
+    conn.run(|c| {
+        diesel::sql_query(
+            "SELECT tags.name, COUNT(links.id) AS links \
+             FROM tags \
+             LEFT JOIN link_tags ON link_tags.tag_id = tags.id \
+             LEFT JOIN links ON links.id = link_tags.link_id AND links.deleted_at IS NULL \
+             GROUP BY tags.name \
+             ORDER BY tags.name",
+        )
+        .load::<TagCount>(c)
+    })
+    .await
+}
+
+pub async fn delete_all(conn: &DbConn) -> QueryResult<usize> {
+    conn.run(|c| diesel::delete(tags::table).execute(c)).await
+}
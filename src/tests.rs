@@ -1,10 +1,29 @@
+use crate::api::CampaignResponse;
 use crate::api::Error;
 use crate::api::LinkResponse;
+use crate::api::WebhookResponse;
+use crate::cache::{CacheStats, LinkCache};
+use crate::campaign::Campaign;
+use crate::checker::{DomainBlocklist, SafeBrowsing, UrlChecker, UrlCheckers};
+use crate::crawler::{self, Device, UserAgent};
+use crate::domain::Domain;
+use crate::geo::{GeoDatabase, Location};
+use crate::link::{InsertError, LinkAttributes};
+use crate::short_code::{self, RandomGenerator, SequenceGenerator, ShortCodeGenerator};
+use crate::sink::{
+    ClickEvent, ClickSink, ClickSinks, HttpBatchSink, JsonLinesSink, PgNotifySink, SinkStats,
+};
+use crate::visit::{
+    ChannelCounts, GroupCount, Referrer, TrustedProxies, VisitBreakdown, VisitRecorder,
+};
+use crate::webhook::{Delivery, Webhook};
 
 use super::rocket;
 use super::Link;
 use rocket::http::{Header, Status};
 use rocket::local::asynchronous::Client;
+use std::io::{Read, Write};
+use std::sync::Arc;
 
 static DB_LOCK: parking_lot::Mutex<()> = parking_lot::const_mutex(());
 
@@ -20,12 +39,121 @@ macro_rules! run_test {
             let $conn = db.expect("failed to get database connection for testing");
 
             Link::delete_all(&$conn).await.expect("failed to delete links");
+            Domain::delete_all(&$conn).await.expect("failed to delete domains");
+            Campaign::delete_all(&$conn).await.expect("failed to delete campaigns");
+            crate::tag::delete_all(&$conn).await.expect("failed to delete tags");
+            Webhook::delete_all(&$conn).await.expect("failed to delete webhooks");
 
             $block
         })
     }};
 }
 
+// Redirects only buffer visits, this writes them out so counts can be checked
+// Requests without a User-Agent count as bots, which aren't visitors
+const BROWSER: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:105.0) Gecko/20100101 Firefox/105.0";
+
+async fn flush_visits(client: &Client, conn: &super::DbConn) {
+    client
+        .rocket()
+        .state::<VisitRecorder>()
+        .expect("visit recorder")
+        .flush(conn)
+        .await
+        .expect("failed to flush visits");
+}
+
+// Answers every request with `body`, returns the server's base URL
+fn mock_http_server(content_type: &'static str, body: &'static str) -> String {
+    mock_server(format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        content_type,
+        body.len(),
+        body
+    ))
+}
+
+fn mock_redirect_server(location: &str) -> String {
+    mock_server(format!(
+        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location
+    ))
+}
+
+fn mock_server(response: String) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("mock server");
+    let address = listener.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut buffer = [0; 8192];
+            let _ = stream.read(&mut buffer);
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+
+    format!("http://{}", address)
+}
+
+// Answers every request with a 200 and passes on each request's body
+fn mock_capturing_server() -> (String, std::sync::mpsc::Receiver<String>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("mock server");
+    let address = listener.local_addr().unwrap();
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut request = vec![];
+            let mut buffer = [0; 8192];
+
+            // Bodies can come in after the headers, so read until there's Content-Length of it
+            while let Ok(read) = stream.read(&mut buffer) {
+                if read == 0 {
+                    break;
+                }
+
+                request.extend_from_slice(&buffer[..read]);
+
+                let text = String::from_utf8_lossy(&request);
+
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+
+                    if body.len() >= length {
+                        let _ = sender.send(body.to_string());
+                        break;
+                    }
+                }
+            }
+
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        }
+    });
+
+    (format!("http://{}", address), receiver)
+}
+
+fn blocklist_file(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, contents).expect("blocklist file");
+
+    path
+}
+
 #[test]
 fn invalid_url() {
     run_test!(|client, conn| {
@@ -147,3 +275,1840 @@ fn delete() {
         assert_eq!(response.status(), Status::NoContent);
     })
 }
+
+#[test]
+fn canonical_url() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "HTTPS://WWW.Google.com:443/search?q=rust&utm_source=email&a=1", "visible": true }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+        assert_eq!(
+            response.into_json::<LinkResponse>().await.unwrap().canonical_url,
+            "https://www.google.com/search?a=1&q=rust"
+        );
+    })
+}
+
+#[test]
+fn backfill_canonical_urls() {
+    use diesel::RunQueryDsl;
+
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://WWW.Google.com/?utm_source=email", "visible": true }"#)
+            .dispatch()
+            .await;
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+
+        // As the migration leaves links that existed before canonical_url
+        conn.run(move |c| {
+            diesel::sql_query(format!("UPDATE links SET canonical_url = '' WHERE id = {}", id))
+                .execute(c)
+        })
+        .await
+        .expect("clear canonical_url");
+
+        assert_eq!(Link::backfill_canonical_urls(&conn).await, Ok(1));
+        assert_eq!(
+            Link::find(id, &conn).await.unwrap().canonical_url,
+            "https://www.google.com"
+        );
+        assert_eq!(Link::backfill_canonical_urls(&conn).await, Ok(0));
+    })
+}
+
+#[test]
+fn canonicalize() {
+    assert_eq!(
+        crate::canonical::canonicalize("http://Example.com:80/").unwrap(),
+        "http://example.com"
+    );
+    assert_eq!(
+        crate::canonical::canonicalize("https://bücher.example/?fbclid=abc").unwrap(),
+        "https://xn--bcher-kva.example"
+    );
+    assert_eq!(crate::canonical::canonicalize("not a url"), None);
+}
+
+#[test]
+fn disallowed_scheme() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "javascript:alert(1)", "visible": true }"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(
+            response.into_json::<Error>().await.unwrap().error,
+            "URL scheme 'javascript' is not allowed"
+        );
+    })
+}
+
+#[test]
+fn chained_short_link() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://bit.ly/abc123", "visible": true }"#)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(
+            response.into_json::<Error>().await.unwrap().error,
+            "URL cannot point to another short link"
+        );
+    })
+}
+
+#[test]
+fn update() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let response = client
+            .put(format!("/api/links/{}", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.rust-lang.org" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<LinkResponse>().await.unwrap().canonical_url,
+            "https://www.rust-lang.org"
+        );
+    })
+}
+
+#[test]
+fn domain_blocklist() {
+    rocket::async_test(async {
+        let path = blocklist_file("kickshort_blocklist", "# spam\nevil.com\n");
+        let blocklist = DomainBlocklist::new(path.clone());
+        let url = url::Url::parse("https://www.evil.com/win").unwrap();
+
+        assert_eq!(
+            blocklist.check(&url).await,
+            Some("Host 'www.evil.com' is blocklisted".to_string())
+        );
+
+        std::fs::write(&path, "other.com\n").unwrap();
+        blocklist.reload().unwrap();
+
+        assert_eq!(blocklist.check(&url).await, None);
+    })
+}
+
+#[test]
+fn safe_browsing() {
+    rocket::async_test(async {
+        let flagged = SafeBrowsing::new(
+            mock_http_server(
+                "application/json",
+                r#"{"matches": [{"threatType": "MALWARE", "threat": {"url": "https://www.google.com/"}}]}"#,
+            ),
+            "key".to_string(),
+        );
+        let clean = SafeBrowsing::new(mock_http_server("application/json", "{}"), "key".to_string());
+        let url = url::Url::parse("https://www.google.com").unwrap();
+        let other = url::Url::parse("https://www.rust-lang.org").unwrap();
+
+        assert_eq!(
+            flagged.check(&url).await,
+            Some("URL has been flagged as MALWARE".to_string())
+        );
+        assert_eq!(clean.check(&url).await, None);
+
+        // One request for both, matched back to the URL it's about
+        assert_eq!(
+            flagged.check_all(&[other, url]).await,
+            vec![None, Some("URL has been flagged as MALWARE".to_string())]
+        );
+    })
+}
+
+#[test]
+fn recheck_disables_flagged_links() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let path = blocklist_file("kickshort_recheck_blocklist", "google.com\n");
+        let mut checkers = UrlCheckers::default();
+        checkers.push(Arc::new(DomainBlocklist::new(path)));
+
+        let cache = client.rocket().state::<LinkCache>().expect("link cache");
+        let pool = super::DbConn::pool(client.rocket()).expect("database pool");
+
+        assert_eq!(checkers.recheck_links(cache, pool).await, Ok(1));
+        assert!(Link::find(id, &conn).await.unwrap().is_disabled());
+    })
+}
+
+#[test]
+fn disable_and_enable() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let link = response.into_json::<LinkResponse>().await.unwrap();
+        let hash = link.short_url.replace(
+            &std::env::var("WHO_AM_I").expect("WHO_AM_I must be set"),
+            ""
+        );
+
+        let response = client
+            .post(format!("/api/links/{}/disable", link.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"reason": "Spam"}"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_json::<LinkResponse>().await.unwrap().disabled_reason,
+            Some("Spam".to_string())
+        );
+
+        let response = client.get(hash.clone()).dispatch().await;
+
+        assert_eq!(response.status(), Status::Gone);
+
+        let response = client
+            .post(format!("/api/links/{}/enable", link.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client.get(hash).dispatch().await;
+
+        assert_eq!(response.status(), Status::SeeOther);
+    })
+}
+
+#[test]
+fn restore() {
+    run_test!(|client, conn| {
+        let response = client.post("/api/links")
+                             .header(Header::new("Content-Type", "application/json"))
+                             .header(Header::new("X-Api-Key", "secret"))
+                             .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "restoreme" }"#)
+                             .dispatch()
+                             .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+
+        let response = client.delete(format!("/api/links/{}", id))
+                             .header(Header::new("Content-Type", "application/json"))
+                             .header(Header::new("X-Api-Key", "secret"))
+                             .dispatch()
+                             .await;
+
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(client.get("/restoreme").dispatch().await.status(), Status::NotFound);
+
+        let response = client.post("/api/links")
+                             .header(Header::new("Content-Type", "application/json"))
+                             .header(Header::new("X-Api-Key", "secret"))
+                             .body(r#"{"url": "https://www.rust-lang.org", "visible": true, "custom_hash": "restoreme" }"#)
+                             .dispatch()
+                             .await;
+
+        assert_eq!(response.status(), Status::Conflict);
+
+        let response = client.post(format!("/api/links/{}/restore", id))
+                             .header(Header::new("Content-Type", "application/json"))
+                             .header(Header::new("X-Api-Key", "secret"))
+                             .dispatch()
+                             .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(client.get("/restoreme").dispatch().await.status(), Status::SeeOther);
+    })
+}
+
+#[test]
+fn purge_deleted() {
+    run_test!(|client, conn| {
+        let response = client.post("/api/links")
+                             .header(Header::new("Content-Type", "application/json"))
+                             .header(Header::new("X-Api-Key", "secret"))
+                             .body(r#"{"url": "https://www.google.com", "visible": true }"#)
+                             .dispatch()
+                             .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let link = Link::find(id, &conn).await.unwrap();
+
+        assert!(link.delete(&conn).await);
+
+        let cutoff = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(1);
+
+        assert_eq!(Link::purge_deleted(cutoff, &conn).await, Ok(1));
+        assert!(Link::find_deleted(id, &conn).await.is_err());
+    })
+}
+
+#[test]
+fn sequence_short_codes() {
+    let generator = SequenceGenerator::new("abc0123456789".chars().collect(), 4);
+    let codes: std::collections::HashSet<String> = (0..20_000).map(|value| generator.encode(value)).collect();
+
+    assert_eq!(codes.len(), 20_000);
+    assert_eq!(generator.encode(1).len(), 4);
+    assert_eq!(generator.encode(13 * 13 * 13 * 13).len(), 5);
+    assert!(codes.iter().all(|code| code.chars().all(|c| "abc0123456789".contains(c))));
+
+    // 36^25 doesn't fit in a u128
+    let generator = SequenceGenerator::new(short_code::DEFAULT_ALPHABET.chars().collect(), 25);
+
+    assert_eq!(generator.encode(u64::MAX).len(), 25);
+    assert_ne!(generator.encode(1), generator.encode(2));
+}
+
+#[test]
+fn short_code_alphabet() {
+    assert_eq!(short_code::parse_alphabet("aab-_"), Ok(vec!['a', 'b', '-', '_']));
+    assert!(short_code::parse_alphabet("abcABC").is_err());
+    assert!(short_code::parse_alphabet("ab/?#").is_err());
+    assert!(short_code::parse_alphabet("aaa").is_err());
+}
+
+#[test]
+fn random_short_codes() {
+    run_test!(|_client, conn| {
+        let generator = RandomGenerator::new("xyz".chars().collect(), 12);
+        let code = generator.generate(&conn).await.unwrap();
+
+        assert_eq!(code.len(), 12);
+        assert!(code.chars().all(|c| "xyz".contains(c)));
+    })
+}
+
+// Hands out `codes` in order, repeating the last one once they run out
+struct ScriptedGenerator {
+    codes: Vec<&'static str>,
+    calls: std::sync::atomic::AtomicUsize,
+}
+
+impl ScriptedGenerator {
+    fn new(codes: Vec<&'static str>) -> Self {
+        ScriptedGenerator {
+            codes,
+            calls: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[rocket::async_trait]
+impl ShortCodeGenerator for ScriptedGenerator {
+    async fn generate(&self, _conn: &super::DbConn) -> Result<String, String> {
+        let call = self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        Ok(self.codes[call.min(self.codes.len() - 1)].to_string())
+    }
+}
+
+fn link_attributes(url: &str) -> LinkAttributes {
+    LinkAttributes {
+        url: url.to_string(),
+        visible: true,
+        custom_hash: None,
+        title: None,
+        description: None,
+        image_url: None,
+        domain_id: None,
+        campaign_id: None,
+        interstitial: false,
+        tags: vec![],
+    }
+}
+
+#[test]
+fn taken_generated_hash_is_retried() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "taken" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let generator = ScriptedGenerator::new(vec!["taken", "taken", "fresh"]);
+        let link = Link::insert_with(link_attributes("https://www.rust-lang.org"), &generator, &conn)
+            .await
+            .unwrap();
+
+        assert_eq!(link.hash, "fresh");
+        assert_eq!(generator.calls(), 3);
+
+        let generator = ScriptedGenerator::new(vec!["taken"]);
+        let result =
+            Link::insert_with(link_attributes("https://www.rust-lang.org"), &generator, &conn).await;
+
+        assert!(matches!(result, Err(InsertError::Database(_))));
+        assert_eq!(generator.calls(), short_code::MAX_ATTEMPTS);
+    })
+}
+
+#[test]
+fn custom_hash_is_case_folded() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "MyLink" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+        assert!(response.into_json::<LinkResponse>().await.unwrap().short_url.ends_with("/mylink"));
+        assert_eq!(client.get("/MyLink").dispatch().await.status(), Status::SeeOther);
+    })
+}
+
+#[test]
+fn invalid_custom_hash() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "a/b" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(
+            response.into_json::<Error>().await.unwrap().error,
+            "Custom hash can only contain letters, numbers, dashes and underscores"
+        );
+    })
+}
+
+#[test]
+fn reserved_custom_hash() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "Public" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(
+            response.into_json::<Error>().await.unwrap().error,
+            "Custom hash 'public' is reserved"
+        );
+    })
+}
+
+#[test]
+fn custom_hash_taken() {
+    run_test!(|client, conn| {
+        for expected in [Status::Created, Status::Conflict] {
+            let response = client
+                .post("/api/links")
+                .header(Header::new("Content-Type", "application/json"))
+                .header(Header::new("X-Api-Key", "secret"))
+                .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "taken" }"#)
+                .dispatch()
+                .await;
+
+            assert_eq!(response.status(), expected);
+
+            if expected == Status::Conflict {
+                assert_eq!(response.into_json::<Error>().await.unwrap().error, "Alias already taken");
+            }
+        }
+    })
+}
+
+#[test]
+fn hash_lookup_is_case_insensitive() {
+    use diesel::RunQueryDsl;
+
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+
+        conn.run(move |c| {
+            diesel::sql_query(format!("UPDATE links SET hash = 'MiXeD' WHERE id = {}", id))
+                .execute(c)
+        })
+        .await
+        .expect("update hash");
+
+        assert_eq!(client.get("/mixed").dispatch().await.status(), Status::SeeOther);
+        assert_eq!(client.get("/MIXED").dispatch().await.status(), Status::SeeOther);
+
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "mixed" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Conflict);
+    })
+}
+
+#[test]
+fn vanity_domains() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/domains")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"host": "go.example.com"}"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.rust-lang.org", "visible": true, "custom_hash": "promo", "domain": "go.example.com" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+        assert_eq!(
+            response.into_json::<LinkResponse>().await.unwrap().short_url,
+            "https://go.example.com/promo"
+        );
+
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "promo" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let response = client
+            .get("/promo")
+            .header(Header::new("Host", "go.example.com"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.headers().get_one("Location"), Some("https://www.rust-lang.org"));
+
+        let response = client.get("/promo").dispatch().await;
+
+        assert_eq!(response.headers().get_one("Location"), Some("https://www.google.com"));
+    })
+}
+
+#[test]
+fn campaigns() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/campaigns")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"name": "Spring Promo", "description": "Flyers"}"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let campaign_id = response.into_json::<CampaignResponse>().await.unwrap().id;
+
+        for url in ["https://www.google.com", "https://www.rust-lang.org"] {
+            let response = client
+                .post("/api/links")
+                .header(Header::new("Content-Type", "application/json"))
+                .header(Header::new("X-Api-Key", "secret"))
+                .body(format!(r#"{{"url": "{}", "visible": true, "campaign_id": {} }}"#, url, campaign_id))
+                .dispatch()
+                .await;
+
+            let hash = response.into_json::<LinkResponse>().await.unwrap().short_url.replace(
+                &std::env::var("WHO_AM_I").expect("WHO_AM_I must be set"),
+                ""
+            );
+
+            client
+                .get(hash)
+                .header(Header::new("User-Agent", BROWSER))
+                .dispatch()
+                .await;
+        }
+
+        client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.example.com", "visible": true }"#)
+            .dispatch()
+            .await;
+
+        flush_visits(&client, &conn).await;
+
+        let response = client
+            .get(format!("/api/campaigns/{}", campaign_id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+        let campaign = response.into_json::<CampaignResponse>().await.unwrap();
+
+        assert_eq!(campaign.links, 2);
+        assert_eq!(campaign.visitors, 2);
+
+        let response = client
+            .get(format!("/api/links?campaign={}", campaign_id))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(Link::paginate(&conn, 1, 10, Some(campaign_id), None).await.unwrap().0.len(), 2);
+
+        let link = Link::paginate(&conn, 1, 10, Some(campaign_id), None).await.unwrap().0.remove(0);
+        let response = client
+            .put(format!("/api/links/{}", link.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"campaign_id": null }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(Link::find(link.id, &conn).await.unwrap().campaign_id, None);
+
+        let response = client
+            .get("/api/campaigns")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+        let campaigns = response.into_json::<Vec<CampaignResponse>>().await.unwrap();
+
+        assert_eq!(campaigns.len(), 1);
+        assert_eq!(campaigns[0].links, 1);
+        assert_eq!(campaigns[0].visitors, 1);
+    })
+}
+
+#[test]
+fn tags() {
+    run_test!(|client, conn| {
+        for (url, tags) in [
+            ("https://www.google.com", r#"["Email", "q4"]"#),
+            ("https://www.rust-lang.org", r#"["email"]"#),
+            ("https://www.example.com", r#"["support"]"#),
+        ] {
+            let response = client
+                .post("/api/links")
+                .header(Header::new("Content-Type", "application/json"))
+                .header(Header::new("X-Api-Key", "secret"))
+                .body(format!(r#"{{"url": "{}", "visible": true, "tags": {} }}"#, url, tags))
+                .dispatch()
+                .await;
+
+            assert_eq!(response.status(), Status::Created);
+
+            if url == "https://www.google.com" {
+                assert_eq!(response.into_json::<LinkResponse>().await.unwrap().tags, vec!["email", "q4"]);
+            }
+        }
+
+        let any = crate::link::TagFilter::Any(vec!["q4".to_string(), "support".to_string()]);
+        let all = crate::link::TagFilter::All(vec!["email".to_string(), "q4".to_string()]);
+
+        assert_eq!(Link::paginate(&conn, 1, 10, None, Some(any)).await.unwrap().0.len(), 2);
+        assert_eq!(Link::paginate(&conn, 1, 10, None, Some(all)).await.unwrap().0.len(), 1);
+
+        let response = client
+            .get("/api/links?tags=email,q4&tag_match=all")
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let counts = crate::tag::counts(&conn).await.unwrap();
+        let counts: Vec<(&str, i64)> = counts.iter().map(|tag| (tag.name.as_str(), tag.links)).collect();
+
+        assert_eq!(counts, vec![("email", 2), ("q4", 1), ("support", 1)]);
+
+        let link = Link::paginate(&conn, 1, 10, None, None)
+            .await
+            .unwrap()
+            .0
+            .into_iter()
+            .find(|link| link.url == "https://www.rust-lang.org")
+            .unwrap();
+        let response = client
+            .put(format!("/api/links/{}", link.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"tags": ["Support"] }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.into_json::<LinkResponse>().await.unwrap().tags, vec!["support"]);
+
+        let counts = crate::tag::counts(&conn).await.unwrap();
+        let counts: Vec<(&str, i64)> = counts.iter().map(|tag| (tag.name.as_str(), tag.links)).collect();
+
+        assert_eq!(counts, vec![("email", 1), ("q4", 1), ("support", 2)]);
+    })
+}
+
+#[test]
+fn fetch_metadata() {
+    run_test!(|client, conn| {
+        let server = mock_http_server(
+            "text/html; charset=utf-8",
+            r#"<html><head>
+                <title> Kicksite
+                  Home </title>
+                <meta name="description" content="Martial arts software">
+                <meta property="og:image" content="/images/card.png">
+                <link rel="shortcut icon" href="/icon.png">
+            </head><body></body></html>"#,
+        );
+
+        let metadata = crate::metadata::fetch(
+            &crate::http::fetch_client(std::time::Duration::from_secs(5), true),
+            &server,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(metadata.title, Some("Kicksite Home".to_string()));
+        assert_eq!(metadata.description, Some("Martial arts software".to_string()));
+        assert_eq!(metadata.image_url, Some(format!("{}/images/card.png", server)));
+        assert_eq!(metadata.favicon_url, Some(format!("{}/icon.png", server)));
+
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "title": "Mine" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let link = Link::find(id, &conn).await.unwrap();
+        let link = link.apply_metadata(metadata, &conn).await.unwrap().unwrap();
+
+        assert_eq!(link.title, Some("Mine".to_string()));
+        assert_eq!(link.description, Some("Martial arts software".to_string()));
+        assert!(link.metadata_fetched_at.is_some());
+    })
+}
+
+#[test]
+fn fetch_metadata_skips_non_html() {
+    rocket::async_test(async {
+        let server = mock_http_server("application/json", "{}");
+        let client = crate::http::fetch_client(std::time::Duration::from_secs(5), true);
+
+        assert!(crate::metadata::fetch(&client, &server).await.is_err());
+    })
+}
+
+#[test]
+fn fetch_metadata_refuses_private_addresses() {
+    rocket::async_test(async {
+        let client = crate::http::fetch_client(std::time::Duration::from_secs(5), false);
+
+        let server = mock_redirect_server("http://169.254.169.254/latest/meta-data/");
+        let error = crate::metadata::fetch(&client, &server).await.unwrap_err();
+        assert!(error.contains("private or local address"), "{}", error);
+
+        let server = mock_http_server("text/html", "<title>Internal</title>");
+        let local = server.replace("127.0.0.1", "localhost");
+        let error = crate::metadata::fetch(&client, &local).await.unwrap_err();
+        assert!(error.contains("private or local address"), "{}", error);
+    })
+}
+
+#[test]
+fn social_preview_for_crawlers() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "title": "Search", "custom_hash": "unfurl" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+
+        let response = client
+            .get("/unfurl")
+            .header(Header::new("User-Agent", "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let body = response.into_string().await.unwrap();
+
+        assert!(body.contains(r#"<meta property="og:title" content="Search">"#));
+        assert!(body.contains(r#"<meta name="twitter:card" content="summary">"#));
+        assert_eq!(Link::find(id, &conn).await.unwrap().visitors, 0);
+
+        let response = client
+            .get("/unfurl")
+            .header(Header::new("User-Agent", "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7)"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::SeeOther);
+    })
+}
+
+#[test]
+fn unfurlers_from_user_agent() {
+    let unfurler = |user_agent: &str| UserAgent(Some(user_agent.to_string())).is_unfurler();
+
+    assert!(unfurler("WhatsApp/2.23.20.0 A"));
+    assert!(unfurler("Mozilla/5.0 (compatible; Pinterestbot/1.0; +http://www.pinterest.com/bot.html)"));
+    assert!(unfurler("Mozilla/5.0 (Windows NT 6.1; WOW64) SkypeUriPreview Preview/0.5"));
+
+    // In-app browsers are people following the link
+    assert!(!unfurler(
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Mobile/15E148 WhatsApp/23.20.79"
+    ));
+    assert!(!unfurler(
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 16_6 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Mobile/15E148 [Pinterest/iOS]"
+    ));
+    assert!(!unfurler(
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Teams/1.6.00.27573 Chrome/85.0.4183.121 Electron/10.4.7 Safari/537.36"
+    ));
+    assert!(!UserAgent(None).is_unfurler());
+}
+
+#[test]
+fn preview_and_interstitial() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "title": "Search", "custom_hash": "peek" }"#)
+            .dispatch()
+            .await;
+
+        let link = response.into_json::<LinkResponse>().await.unwrap();
+
+        assert!(!link.interstitial);
+
+        for path in ["/peek+", "/peek?preview"] {
+            let response = client.get(path).dispatch().await;
+
+            assert_eq!(response.status(), Status::Ok);
+
+            let body = response.into_string().await.unwrap();
+
+            assert!(body.contains("https://www.google.com"));
+            assert!(body.contains("Search"));
+            assert!(body.contains(r#"href="/peek?go""#));
+        }
+
+        assert_eq!(Link::find(link.id, &conn).await.unwrap().visitors, 0);
+
+        let response = client
+            .put(format!("/api/links/{}", link.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"interstitial": true }"#)
+            .dispatch()
+            .await;
+
+        assert!(response.into_json::<LinkResponse>().await.unwrap().interstitial);
+
+        let response = client.get("/peek").dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(Link::find(link.id, &conn).await.unwrap().visitors, 0);
+
+        let response = client
+            .get("/peek?go")
+            .header(Header::new("User-Agent", BROWSER))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::SeeOther);
+
+        flush_visits(&client, &conn).await;
+
+        assert_eq!(Link::find(link.id, &conn).await.unwrap().visitors, 1);
+    })
+}
+
+fn decode_qr(image: image::GrayImage) -> String {
+    let mut image = rqrr::PreparedImage::prepare(image);
+    let grids = image.detect_grids();
+
+    assert_eq!(grids.len(), 1);
+
+    grids[0].decode().expect("decodable QR code").1
+}
+
+#[test]
+fn qr_codes() {
+    run_test!(|client, _conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "flyer" }"#)
+            .dispatch()
+            .await;
+
+        let link = response.into_json::<LinkResponse>().await.unwrap();
+
+        assert!(link.qr_url.ends_with(&format!("/api/links/{}/qr.svg", link.id)));
+
+        let response = client
+            .get(format!("/api/links/{}/qr.png?size=400&ec=H&fg=112233&bg=%23ffeedd", link.id))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(rocket::http::ContentType::PNG));
+
+        let png = image::load_from_memory(&response.into_bytes().await.unwrap()).unwrap();
+
+        assert!(png.width() <= 400);
+        assert_eq!(decode_qr(png.to_luma8()), format!("{}?qr", link.short_url));
+
+        let response = client
+            .get(format!("/api/links/{}/qr.svg?margin=2", link.id))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(rocket::http::ContentType::SVG));
+
+        // Rasterizes the SVG's module path, 10px a module, to decode it
+        let svg = response.into_string().await.unwrap();
+        let dimension: u32 = svg
+            .split("viewBox=\"0 0 ")
+            .nth(1)
+            .and_then(|rest| rest.split(' ').next())
+            .and_then(|dimension| dimension.parse().ok())
+            .unwrap();
+        let path = svg.split(" d=\"").nth(1).and_then(|rest| rest.split('"').next()).unwrap();
+        let dark: Vec<(u32, u32)> = path
+            .split('M')
+            .filter(|module| !module.is_empty())
+            .map(|module| {
+                let (x, rest) = module.split_once(',').unwrap();
+                let y = rest.split('h').next().unwrap();
+                (x.parse().unwrap(), y.parse().unwrap())
+            })
+            .collect();
+        let raster = image::GrayImage::from_fn(dimension * 10, dimension * 10, |x, y| {
+            if dark.contains(&(x / 10, y / 10)) {
+                image::Luma([0])
+            } else {
+                image::Luma([255])
+            }
+        });
+
+        assert_eq!(decode_qr(raster), format!("{}?qr", link.short_url));
+
+        let response = client
+            .get(format!("/api/links/{}/qr.png?ec=X", link.id))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        let response = client
+            .get("/api/links/0/qr.svg")
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::NotFound);
+    })
+}
+
+#[test]
+fn visits_split_by_channel() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "kiosk" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+
+        for path in ["/kiosk", "/kiosk?qr", "/kiosk?qr"] {
+            let response = client.get(path).dispatch().await;
+
+            assert_eq!(response.status(), Status::SeeOther);
+            assert_eq!(response.headers().get_one("Location"), Some("https://www.google.com"));
+        }
+
+        flush_visits(&client, &conn).await;
+
+        let response = client
+            .get(format!("/api/links/{}", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        let link = response.into_json::<LinkResponse>().await.unwrap();
+
+        assert_eq!(link.channels, ChannelCounts { direct: 1, qr: 2 });
+    })
+}
+
+async fn cache_stats(client: &Client) -> CacheStats {
+    client
+        .get("/api/cache")
+        .header(Header::new("Content-Type", "application/json"))
+        .header(Header::new("X-Api-Key", "secret"))
+        .dispatch()
+        .await
+        .into_json::<CacheStats>()
+        .await
+        .unwrap()
+}
+
+#[test]
+fn redirect_cache() {
+    run_test!(|client, _conn| {
+        let before = cache_stats(&client).await;
+
+        // Unknown hashes are cached too, and creating the link replaces that
+        assert_eq!(client.get("/cached").dispatch().await.status(), Status::NotFound);
+        assert_eq!(client.get("/cached").dispatch().await.status(), Status::NotFound);
+
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "cached" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let response = client.get("/cached").dispatch().await;
+
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("https://www.google.com"));
+
+        client
+            .put(format!("/api/links/{}", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.example.com" }"#)
+            .dispatch()
+            .await;
+
+        let response = client.get("/CACHED").dispatch().await;
+
+        assert_eq!(response.headers().get_one("Location"), Some("https://www.example.com"));
+
+        client
+            .post(format!("/api/links/{}/disable", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{}"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(client.get("/cached").dispatch().await.status(), Status::Gone);
+
+        client
+            .post(format!("/api/links/{}/enable", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+        client
+            .delete(format!("/api/links/{}", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(client.get("/cached").dispatch().await.status(), Status::NotFound);
+
+        let after = cache_stats(&client).await;
+
+        // Misses: the first unknown lookup, then one after each change
+        assert_eq!(after.misses - before.misses, 5);
+        assert_eq!(after.hits - before.hits, 1);
+    })
+}
+
+#[test]
+fn sink_stats() {
+    run_test!(|client, _conn| {
+        let response = client
+            .get("/api/sinks")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_json::<SinkStats>().await.unwrap().dropped, 0);
+    })
+}
+
+#[test]
+fn cache_miss_racing_invalidate() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "racing" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let link = Link::find(id, &conn).await.unwrap();
+        let cache = LinkCache::new(
+            10,
+            std::time::Duration::from_secs(60),
+            std::time::Duration::from_secs(60),
+        );
+        let pool = super::DbConn::pool(client.rocket()).expect("database pool");
+
+        // A lookup that read "not found" before the link was created doesn't cache it
+        let miss = cache.miss("racing", None);
+        cache.invalidate(&link);
+        miss.put(None);
+
+        assert_eq!(cache.stats().entries, 0);
+
+        let found = cache.find_by_hash("racing".to_string(), None, pool).await;
+
+        assert_eq!(found.unwrap().map(|link| link.id), Some(id));
+        assert_eq!(cache.stats().entries, 1);
+    })
+}
+
+#[test]
+fn bot_visits_are_counted_separately() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "robots" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let user_agents = [
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/106.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 16_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.0 Mobile/15E148 Safari/604.1",
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Mozilla/5.0 (compatible; UptimeRobot/2.0; http://www.uptimerobot.com/)",
+            "curl/7.85.0",
+        ];
+
+        for user_agent in user_agents {
+            let response = client
+                .get("/robots")
+                .header(Header::new("User-Agent", user_agent))
+                .dispatch()
+                .await;
+
+            assert_eq!(response.status(), Status::SeeOther);
+        }
+
+        flush_visits(&client, &conn).await;
+
+        let response = client
+            .get(format!("/api/links/{}", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        let link = response.into_json::<LinkResponse>().await.unwrap();
+
+        assert_eq!(link.human_visits, 2);
+        assert_eq!(link.bot_visits, 3);
+    })
+}
+
+#[test]
+fn bot_visits_dont_cross_click_threshold() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/webhooks")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://hooks.example.com/clicks", "events": ["link.click_threshold"], "click_threshold": 2 }"#)
+            .dispatch()
+            .await;
+
+        let webhook = response.into_json::<WebhookResponse>().await.unwrap();
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "crawled" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+
+        for user_agent in ["Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)", "curl/7.85.0", ""] {
+            client
+                .get("/crawled")
+                .header(Header::new("User-Agent", user_agent))
+                .dispatch()
+                .await;
+        }
+
+        client
+            .get("/crawled")
+            .header(Header::new("User-Agent", BROWSER))
+            .dispatch()
+            .await;
+
+        flush_visits(&client, &conn).await;
+
+        assert_eq!(Link::find(id, &conn).await.unwrap().visitors, 1);
+
+        let response = client
+            .get(format!("/api/webhooks/{}/deliveries", webhook.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert!(response.into_json::<Vec<Delivery>>().await.unwrap().is_empty());
+    })
+}
+
+#[test]
+fn bots_from_user_agent() {
+    let bot = |user_agent: &str| UserAgent(Some(user_agent.to_string())).is_bot();
+
+    // Only woothee knows this one
+    assert!(bot("Mediapartners-Google"));
+    assert!(bot("Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)"));
+    assert!(bot("python-requests/2.28.1"));
+    assert!(UserAgent(None).is_bot());
+
+    assert!(!bot(
+        "Mozilla/5.0 (Linux; Android 10; CUBOT X30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/106.0.0.0 Mobile Safari/537.36"
+    ));
+}
+
+#[test]
+fn unique_visitors() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "unique" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:105.0) Gecko/20100101 Firefox/105.0";
+        let safari = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.0 Safari/605.1.15";
+        let visits = [
+            ("203.0.113.5", firefox),
+            ("203.0.113.5", firefox),
+            ("203.0.113.5", firefox),
+            ("203.0.113.5", safari),
+            ("198.51.100.7", firefox),
+        ];
+
+        for (ip, user_agent) in visits {
+            client
+                .get("/unique")
+                .remote(format!("{}:4000", ip).parse().unwrap())
+                .header(Header::new("User-Agent", user_agent))
+                .dispatch()
+                .await;
+        }
+
+        // No proxy is trusted in tests, so these are whatever the visitor wants them to be
+        for spoofed in ["192.0.2.1", "192.0.2.2"] {
+            client
+                .get("/unique")
+                .remote("203.0.113.5:4000".parse().unwrap())
+                .header(Header::new("X-Forwarded-For", spoofed))
+                .header(Header::new("X-Real-IP", spoofed))
+                .header(Header::new("User-Agent", firefox))
+                .dispatch()
+                .await;
+        }
+
+        flush_visits(&client, &conn).await;
+
+        let response = client
+            .get(format!("/api/links/{}", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        let link = response.into_json::<LinkResponse>().await.unwrap();
+
+        assert_eq!(link.human_visits, 7);
+        assert_eq!(link.unique_visitors, 3);
+    })
+}
+
+#[test]
+fn client_ip_from_trusted_proxies() {
+    let proxies = TrustedProxies {
+        header: Some("X-Forwarded-For".to_string()),
+        networks: vec![("10.0.0.0".parse().unwrap(), 8)],
+    };
+    let ip = |address: &str| Some(address.parse::<std::net::IpAddr>().unwrap());
+    let load_balancer = ip("10.1.2.3");
+
+    assert_eq!(
+        proxies.client_ip(load_balancer, &["203.0.113.5"]),
+        ip("203.0.113.5")
+    );
+    // The visitor sent their own X-Forwarded-For, which the load balancer appended to
+    assert_eq!(
+        proxies.client_ip(load_balancer, &["192.0.2.1, 203.0.113.5, 10.4.5.6"]),
+        ip("203.0.113.5")
+    );
+    assert_eq!(
+        proxies.client_ip(load_balancer, &["192.0.2.1", "203.0.113.5"]),
+        ip("203.0.113.5")
+    );
+    assert_eq!(
+        proxies.client_ip(load_balancer, &["not an ip"]),
+        load_balancer
+    );
+    assert_eq!(proxies.client_ip(load_balancer, &[]), load_balancer);
+
+    // Only trusted proxies get to say who the visitor is
+    assert_eq!(
+        proxies.client_ip(ip("198.51.100.7"), &["192.0.2.1"]),
+        ip("198.51.100.7")
+    );
+    assert_eq!(proxies.client_ip(None, &["192.0.2.1"]), None);
+}
+
+#[test]
+fn device_breakdown() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "devices" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let user_agents = [
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/106.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/106.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 16_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/16.0 Mobile/15E148 Safari/604.1",
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+        ];
+
+        for user_agent in user_agents {
+            client
+                .get("/devices")
+                .header(Header::new("User-Agent", user_agent))
+                .dispatch()
+                .await;
+        }
+
+        flush_visits(&client, &conn).await;
+
+        let response = client
+            .get(format!("/api/links/{}/breakdown", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let breakdown = response.into_json::<VisitBreakdown>().await.unwrap();
+        let count = |value: &str, visits| GroupCount { value: value.to_string(), visits };
+
+        assert_eq!(breakdown.devices, vec![count("desktop", 2), count("mobile", 1)]);
+        assert_eq!(breakdown.operating_systems, vec![count("Windows 10", 2), count("iPhone", 1)]);
+        assert_eq!(breakdown.browsers, vec![count("Chrome", 2), count("Safari", 1)]);
+        // No GeoIP database is configured for tests
+        assert_eq!(breakdown.countries, vec![count("unknown", 3)]);
+    })
+}
+
+#[test]
+fn device_from_user_agent() {
+    let device = crawler::device(Some(
+        "Mozilla/5.0 (Linux; Android 13; Pixel 7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/106.0.0.0 Mobile Safari/537.36",
+    ));
+
+    assert_eq!(device.kind.as_deref(), Some("mobile"));
+    assert_eq!(device.os.as_deref(), Some("Android"));
+    assert_eq!(device.browser.as_deref(), Some("Chrome"));
+    assert_eq!(crawler::device(None), Device::default());
+}
+
+#[test]
+fn location_from_ip() {
+    // Built by tests/fixtures/geoip_city.py
+    let geo = GeoDatabase::open(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/geoip_city.mmdb"
+    ))
+    .expect("GeoIP fixture");
+
+    assert_eq!(
+        geo.lookup("81.2.69.142".parse().unwrap()),
+        Location {
+            country: Some("GB".to_string()),
+            region: Some("England".to_string()),
+        }
+    );
+    assert_eq!(geo.lookup("192.0.2.1".parse().unwrap()), Location::default());
+}
+
+#[test]
+fn referrers() {
+    run_test!(|client, conn| {
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "sources" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+        let referers = [
+            "https://news.ycombinator.com/item?id=1",
+            "https://news.ycombinator.com/",
+            "https://www.reddit.com/r/rust/",
+            "https://old.reddit.com/r/rust/",
+            "https://t.co/abc",
+        ];
+
+        for referer in referers {
+            client
+                .get("/sources")
+                .header(Header::new("Referer", referer))
+                .header(Header::new("User-Agent", "Mozilla/5.0 (X11; Linux x86_64; rv:105.0) Gecko/20100101 Firefox/105.0"))
+                .dispatch()
+                .await;
+        }
+
+        flush_visits(&client, &conn).await;
+
+        let today = chrono::Utc::now().naive_utc().date().format("%Y-%m-%d");
+        let response = client
+            .get(format!("/api/links/{}/referrers?from={}&to={}&limit=2", id, today, today))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Ok);
+
+        let referrers = response.into_json::<Vec<GroupCount>>().await.unwrap();
+        let count = |value: &str, visits| GroupCount { value: value.to_string(), visits };
+
+        assert_eq!(referrers, vec![count("reddit.com", 2), count("ycombinator.com", 2)]);
+
+        let response = client
+            .get(format!("/api/links/{}/referrers?to=2000-01-01", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert!(response.into_json::<Vec<GroupCount>>().await.unwrap().is_empty());
+
+        let response = client
+            .get(format!("/api/links/{}/referrers?from=yesterday", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    })
+}
+
+#[test]
+fn referrer_domain() {
+    assert_eq!(Referrer::domain("https://www.bbc.co.uk/news"), Some("bbc.co.uk".to_string()));
+    assert_eq!(Referrer::domain("http://192.168.1.10:8080/"), Some("192.168.1.10".to_string()));
+    assert_eq!(Referrer::domain("not a url"), None);
+}
+
+#[test]
+fn webhook_signature() {
+    assert_eq!(
+        crate::webhook::sign("key", "The quick brown fox jumps over the lazy dog"),
+        "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+}
+
+#[test]
+fn webhooks() {
+    use diesel::RunQueryDsl;
+
+    run_test!(|client, conn| {
+        let server = mock_http_server("text/plain", "ok");
+        let response = client
+            .post("/api/webhooks")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(format!(r#"{{"url": "{}/hooks", "events": ["link.created"] }}"#, server))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        let response = client
+            .post("/api/webhooks")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://hooks.example.com/kickshort", "events": ["link.created", "link.click_threshold"], "click_threshold": 2 }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Created);
+
+        let webhook = response.into_json::<WebhookResponse>().await.unwrap();
+        let webhook_id = webhook.id;
+
+        // Private hosts are only allowed with ALLOW_PRIVATE_HOSTS, which the mock server needs
+        conn.run(move |c| {
+            diesel::sql_query(format!("UPDATE webhooks SET url = '{}/hooks' WHERE id = {}", server, webhook_id))
+                .execute(c)
+        })
+        .await
+        .expect("point webhook at mock server");
+
+        assert_eq!(webhook.secret.map(|secret| secret.len()), Some(64));
+
+        let response = client
+            .get(format!("/api/webhooks/{}", webhook.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert!(!response.into_string().await.unwrap().contains("secret"));
+
+        let response = client
+            .post("/api/webhooks")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "ftp://example.com", "events": ["link.exploded"] }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+
+        let response = client
+            .post("/api/links")
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://www.google.com", "visible": true, "custom_hash": "hooked" }"#)
+            .dispatch()
+            .await;
+
+        let id = response.into_json::<LinkResponse>().await.unwrap().id;
+
+        // Not subscribed to updates
+        client
+            .put(format!("/api/links/{}", id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"title": "Search" }"#)
+            .dispatch()
+            .await;
+
+        for _ in 0..3 {
+            client
+                .get("/hooked")
+                .header(Header::new("User-Agent", BROWSER))
+                .dispatch()
+                .await;
+        }
+
+        flush_visits(&client, &conn).await;
+
+        let http = crate::http::fetch_client(std::time::Duration::from_secs(5), true);
+        let pool = super::DbConn::pool(client.rocket()).expect("database pool");
+
+        assert_eq!(crate::webhook::deliver_due(&http, pool).await.unwrap(), 2);
+        assert_eq!(crate::webhook::deliver_due(&http, pool).await.unwrap(), 0);
+
+        let response = client
+            .get(format!("/api/webhooks/{}/deliveries", webhook.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+        let deliveries = response.into_json::<Vec<Delivery>>().await.unwrap();
+        let events: Vec<&str> = deliveries.iter().map(|delivery| delivery.event.as_str()).collect();
+
+        assert_eq!(events, vec!["link.click_threshold", "link.created"]);
+        assert!(deliveries.iter().all(|delivery| delivery.status == "delivered"));
+        assert!(deliveries.iter().all(|delivery| delivery.response_status == Some(200)));
+
+        let payload: LinkResponse = rocket::serde::json::from_str(&deliveries[0].payload).unwrap();
+
+        assert_eq!(payload.id, id);
+        assert!(payload.short_url.ends_with("/hooked"));
+
+        let response = client
+            .put(format!("/api/webhooks/{}", webhook.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"url": "https://hooks.example.com/kickshort", "click_threshold": null }"#)
+            .dispatch()
+            .await;
+
+        let updated = response.into_json::<WebhookResponse>().await.unwrap();
+
+        assert_eq!(updated.click_threshold, None);
+        assert_eq!(updated.secret, None);
+
+        let response = client
+            .put(format!("/api/webhooks/{}", webhook.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .body(r#"{"secret": "a-new-secret-of-some-length" }"#)
+            .dispatch()
+            .await;
+
+        assert_eq!(
+            response.into_json::<WebhookResponse>().await.unwrap().secret,
+            Some("a-new-secret-of-some-length".to_string())
+        );
+
+        let response = client
+            .delete(format!("/api/webhooks/{}", webhook.id))
+            .header(Header::new("Content-Type", "application/json"))
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::NoContent);
+    })
+}
+
+fn click_event(link_id: i32) -> ClickEvent {
+    ClickEvent {
+        link_id,
+        hash: "clicked".to_string(),
+        url: "https://www.google.com".to_string(),
+        channel: "direct".to_string(),
+        is_bot: false,
+        referrer: None,
+        user_agent: Some("curl/7.85.0".to_string()),
+        created_at: chrono::Utc::now().naive_utc(),
+    }
+}
+
+#[test]
+fn json_lines_click_sink() {
+    let path = std::env::temp_dir().join("kickshort-clicks.jsonl");
+    let _ = std::fs::remove_file(&path);
+
+    rocket::async_test(async {
+        let sink = JsonLinesSink::file(path.to_str().unwrap()).expect("click sink file");
+        let sink: Arc<dyn ClickSink> = Arc::new(sink);
+        let sinks = ClickSinks::spawn(vec![sink], std::time::Duration::from_millis(10));
+
+        sinks.emit(click_event(1));
+        sinks.emit(click_event(2));
+
+        // Shutting down writes what's still buffered
+        sinks.shutdown(std::time::Duration::from_secs(5)).await;
+    });
+
+    let contents = std::fs::read_to_string(&path).expect("click sink output");
+    let events: Vec<ClickEvent> = contents
+        .lines()
+        .map(|line| rocket::serde::json::from_str(line).unwrap())
+        .collect();
+
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].link_id, 1);
+    assert_eq!(events[1].link_id, 2);
+}
+
+#[test]
+fn http_batch_click_sink() {
+    let (server, bodies) = mock_capturing_server();
+    let events = vec![click_event(1), click_event(2)];
+
+    rocket::async_test(async {
+        let sink = HttpBatchSink::new(format!("{}/clicks", server));
+
+        sink.write(&events).await.unwrap();
+    });
+
+    let body = bodies
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .expect("click batch");
+
+    assert_eq!(rocket::serde::json::from_str::<Vec<ClickEvent>>(&body).unwrap(), events);
+}
+
+#[test]
+fn postgres_notify_click_sink() {
+    use postgres::fallible_iterator::FallibleIterator;
+
+    let database_url = std::env::var("DATABASE_URL_TEST").expect("DATABASE_URL_TEST must be set");
+    let mut listener =
+        postgres::Client::connect(&database_url, postgres::NoTls).expect("listener connection");
+    listener.batch_execute("LISTEN kickshort_clicks").unwrap();
+
+    let sink = PgNotifySink::new("kickshort_clicks".to_string(), database_url);
+    let oversized = ClickEvent {
+        user_agent: Some("a".repeat(8000)),
+        ..click_event(2)
+    };
+
+    rocket::async_test(async {
+        sink.write(&[click_event(1), oversized, click_event(3)])
+            .await
+            .unwrap();
+    });
+
+    let link_ids: Vec<i32> = listener
+        .notifications()
+        .timeout_iter(std::time::Duration::from_secs(5))
+        .take(2)
+        .map(|notification| {
+            Ok(rocket::serde::json::from_str::<ClickEvent>(notification.payload())
+                .unwrap()
+                .link_id)
+        })
+        .collect()
+        .unwrap();
+
+    // The oversized event was skipped rather than failing the batch
+    assert_eq!(link_ids, vec![1, 3]);
+    assert_eq!(listener.notifications().iter().count().unwrap(), 0);
+}
+
+// Never finishes a write, like a sink whose endpoint has hung
+struct StuckSink;
+
+#[rocket::async_trait]
+impl ClickSink for StuckSink {
+    fn name(&self) -> &'static str {
+        "stuck"
+    }
+
+    async fn write(&self, _events: &[ClickEvent]) -> Result<(), String> {
+        std::future::pending().await
+    }
+}
+
+#[test]
+fn stuck_click_sink_drops_events() {
+    rocket::async_test(async {
+        let sink: Arc<dyn ClickSink> = Arc::new(StuckSink);
+        let sinks = ClickSinks::spawn(vec![sink], std::time::Duration::from_millis(10));
+
+        for id in 0..20_000 {
+            sinks.emit(click_event(id));
+        }
+
+        assert!(sinks.stats().dropped > 0);
+
+        // Shutdown gives up on it rather than hanging
+        sinks.shutdown(std::time::Duration::from_millis(100)).await;
+    });
+}
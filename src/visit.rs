@@ -0,0 +1,651 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::{
+    self,
+    pg::PgConnection,
+    prelude::*,
+    sql_types::{BigInt, Date, Integer, Nullable, Varchar},
+};
+use parking_lot::Mutex;
+use rand::Rng;
+use rocket::fairing::AdHoc;
+use rocket::request::{FromRequest, Outcome};
+use rocket::tokio;
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::{Host, Url};
+
+use crate::{
+    crawler::{self, Device},
+    geo::{GeoDatabase, Location},
+    link::schema::links,
+    webhook, DbConn,
+};
+
+use self::schema::{visitor_salts, visits};
+
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+// Visits past this are dropped while the database is unreachable, rather than
+// growing the buffer forever
+const MAX_PENDING: usize = 100_000;
+// Keeps each insert under Postgres' bind parameter limit
+const INSERT_BATCH_SIZE: usize = 10_000;
+
+// How a visitor got to the short link, QR codes add `?qr` to the short URL
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Direct,
+    Qr,
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Direct => "direct",
+            Channel::Qr => "qr",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct ChannelCounts {
+    pub direct: i64,
+    pub qr: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct NewVisit {
+    pub link_id: i32,
+    pub channel: Channel,
+    pub is_bot: bool,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    pub referrer: Option<String>,
+}
+
+// The IP and user agent only live in memory, they're reduced to a visitor hash when written
+#[derive(Clone, Debug)]
+struct PendingVisit {
+    visit: NewVisit,
+    created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "visits"]
+struct VisitRow {
+    link_id: i32,
+    channel: &'static str,
+    is_bot: bool,
+    visitor_hash: Option<String>,
+    created_at: chrono::NaiveDateTime,
+    country: Option<String>,
+    region: Option<String>,
+    device: Option<String>,
+    os: Option<String>,
+    browser: Option<String>,
+    referrer: Option<String>,
+}
+
+// Buffers visits in memory so redirects never wait on, or fail because of, counting.
+// The buffer is written out every VISIT_FLUSH_INTERVAL seconds and on shutdown.
+#[derive(Clone, Default)]
+pub struct VisitRecorder {
+    pending: Arc<Mutex<Vec<PendingVisit>>>,
+    geo: Option<GeoDatabase>,
+}
+
+impl VisitRecorder {
+    pub fn from_env() -> Self {
+        VisitRecorder {
+            pending: Arc::default(),
+            geo: GeoDatabase::from_env(),
+        }
+    }
+
+    pub fn record(&self, visit: NewVisit) {
+        let mut pending = self.pending.lock();
+
+        if pending.len() < MAX_PENDING {
+            pending.push(PendingVisit {
+                visit,
+                created_at: chrono::Utc::now().naive_utc(),
+            });
+        }
+    }
+
+    // Adds the buffered visits to each link's visitors in one transaction,
+    // they go back in the buffer to retry if it fails
+    pub async fn flush(&self, conn: &DbConn) -> QueryResult<usize> {
+        let batch = std::mem::take(&mut *self.pending.lock());
+
+        if batch.is_empty() {
+            return Ok(0);
+        }
+
+        let result = conn
+            .run({
+                let batch = batch.clone();
+                let geo = self.geo.clone();
+                move |c| {
+                    let details = visit_details(&batch, geo.as_ref());
+
+                    c.transaction(|| write_visits(&batch, details, c))
+                }
+            })
+            .await;
+
+        match result {
+            Ok(visitors) => {
+                webhook::enqueue_click_thresholds(visitors, conn).await;
+
+                Ok(batch.len())
+            }
+            Err(e) => {
+                let mut pending = self.pending.lock();
+                let newer = std::mem::replace(&mut *pending, batch);
+
+                pending.extend(newer);
+                pending.truncate(MAX_PENDING);
+
+                Err(e)
+            }
+        }
+    }
+
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_ignite("Visit Recorder", |rocket| async {
+            rocket
+                .manage(VisitRecorder::from_env())
+                .attach(AdHoc::on_liftoff("Visit Flusher", |rocket| {
+                    Box::pin(async move {
+                        let recorder = rocket.state::<VisitRecorder>().cloned();
+                        let pool = DbConn::pool(rocket).cloned();
+
+                        if let (Some(recorder), Some(pool)) = (recorder, pool) {
+                            tokio::spawn(async move {
+                                loop {
+                                    tokio::time::sleep(flush_interval()).await;
+
+                                    if let Some(conn) = DbConn::from_pool(&pool).await {
+                                        if let Err(e) = recorder.flush(&conn).await {
+                                            log::error!("Failed to flush visits: {}", e);
+                                        }
+                                    }
+                                }
+                            });
+                        }
+                    })
+                }))
+                .attach(AdHoc::on_shutdown("Flush Visits", |rocket| {
+                    Box::pin(async move {
+                        let recorder = rocket.state::<VisitRecorder>();
+                        let pool = DbConn::pool(rocket);
+
+                        if let (Some(recorder), Some(pool)) = (recorder, pool) {
+                            if let Some(conn) = DbConn::from_pool(pool).await {
+                                if let Err(e) = recorder.flush(&conn).await {
+                                    log::error!("Failed to flush visits on shutdown: {}", e);
+                                }
+                            }
+                        }
+                    })
+                }))
+        })
+    }
+}
+
+// Location and device lookups happen when flushing rather than in the redirect,
+// and before the transaction so it isn't held open while they run
+fn visit_details(batch: &[PendingVisit], geo: Option<&GeoDatabase>) -> Vec<(Location, Device)> {
+    batch
+        .iter()
+        .map(|pending| {
+            let location = match (geo, pending.visit.ip) {
+                (Some(geo), Some(ip)) => geo.lookup(ip),
+                _ => Default::default(),
+            };
+
+            (location, crawler::device(pending.visit.user_agent.as_deref()))
+        })
+        .collect()
+}
+
+// `details` lines up with `batch`.
+// Returns each link's visitors before and after the batch, bot visits aren't counted.
+fn write_visits(
+    batch: &[PendingVisit],
+    details: Vec<(Location, Device)>,
+    c: &PgConnection,
+) -> QueryResult<Vec<(i32, i32, i32)>> {
+    let mut counts: HashMap<i32, i32> = HashMap::new();
+
+    // Bots aren't visitors, but links they visited still get a count of 0 so the update
+    // finds out whether the link still exists
+    for pending in batch {
+        let count = counts.entry(pending.visit.link_id).or_default();
+
+        if !pending.visit.is_bot {
+            *count += 1;
+        }
+    }
+
+    let mut visitors = vec![];
+
+    for (link_id, count) in &counts {
+        let after = diesel::update(links::table.find(*link_id))
+            .set(links::visitors.eq(links::visitors + *count))
+            .returning(links::visitors)
+            .get_result::<i32>(c)
+            .optional()?;
+
+        if let Some(after) = after {
+            visitors.push((*link_id, after - count, after));
+        }
+    }
+
+    // Links purged since their visit was buffered have nothing to attach it to
+    let existing: Vec<i32> = visitors.iter().map(|(link_id, _, _)| *link_id).collect();
+
+    let mut salts: HashMap<chrono::NaiveDate, String> = HashMap::new();
+    let mut rows = vec![];
+
+    for (pending, (location, device)) in batch.iter().zip(details) {
+        let visit = &pending.visit;
+
+        if !existing.contains(&visit.link_id) {
+            continue;
+        }
+
+        let day = pending.created_at.date();
+        let salt = match salts.get(&day) {
+            Some(salt) => salt.clone(),
+            None => {
+                let salt = salt_for(day, c)?;
+                salts.insert(day, salt.clone());
+                salt
+            }
+        };
+
+        rows.push(VisitRow {
+            link_id: visit.link_id,
+            channel: visit.channel.as_str(),
+            is_bot: visit.is_bot,
+            visitor_hash: visitor_hash(&salt, visit),
+            created_at: pending.created_at,
+            country: location.country,
+            region: location.region,
+            device: device.kind,
+            os: device.os,
+            browser: device.browser,
+            referrer: visit.referrer.clone(),
+        });
+    }
+
+    for chunk in rows.chunks(INSERT_BATCH_SIZE) {
+        diesel::insert_into(visits::table).values(chunk).execute(c)?;
+    }
+
+    Ok(visitors)
+}
+
+// Each day gets a random salt shared by every instance. Salts are deleted the day after,
+// so visitor hashes can't be linked across days or traced back to an IP.
+fn salt_for(day: chrono::NaiveDate, c: &PgConnection) -> QueryResult<String> {
+    let salt: String = rand::thread_rng()
+        .gen::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    diesel::delete(visitor_salts::table.filter(visitor_salts::day.lt(day - chrono::Duration::days(1))))
+        .execute(c)?;
+
+    diesel::insert_into(visitor_salts::table)
+        .values((visitor_salts::day.eq(day), visitor_salts::salt.eq(salt)))
+        .on_conflict_do_nothing()
+        .execute(c)?;
+
+    visitor_salts::table
+        .find(day)
+        .select(visitor_salts::salt)
+        .first::<String>(c)
+}
+
+fn visitor_hash(salt: &str, visit: &NewVisit) -> Option<String> {
+    let ip = visit.ip?;
+    let digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(ip.to_string())
+        .chain_update("\n")
+        .chain_update(visit.user_agent.as_deref().unwrap_or(""))
+        .finalize();
+
+    Some(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// VISIT_FLUSH_INTERVAL is in seconds
+fn flush_interval() -> Duration {
+    std::env::var("VISIT_FLUSH_INTERVAL")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_FLUSH_INTERVAL)
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct VisitorCounts {
+    pub human: i64,
+    pub bot: i64,
+}
+
+// Everything counted from a link's visits, in one pass over them
+pub struct VisitStats {
+    pub channels: ChannelCounts,
+    pub visitors: VisitorCounts,
+    pub unique_visitors: i64,
+}
+
+#[derive(QueryableByName)]
+struct VisitStatsRow {
+    #[sql_type = "BigInt"]
+    direct: i64,
+    #[sql_type = "BigInt"]
+    qr: i64,
+    #[sql_type = "BigInt"]
+    human: i64,
+    #[sql_type = "BigInt"]
+    bot: i64,
+    #[sql_type = "BigInt"]
+    unique_visitors: i64,
+}
+
+// Unique visitors are counted per day, since the same person hashes differently each day.
+// Bots aren't visitors.
+pub async fn stats(link_id: i32, conn: &DbConn) -> QueryResult<VisitStats> {
+    let row = conn
+        .run(move |c| {
+            diesel::sql_query(
+                "SELECT COUNT(*) FILTER (WHERE channel <> 'qr') AS direct, \
+                 COUNT(*) FILTER (WHERE channel = 'qr') AS qr, \
+                 COUNT(*) FILTER (WHERE NOT is_bot) AS human, \
+                 COUNT(*) FILTER (WHERE is_bot) AS bot, \
+                 COUNT(DISTINCT (created_at::date, visitor_hash)) \
+                 FILTER (WHERE visitor_hash IS NOT NULL AND NOT is_bot) AS unique_visitors \
+                 FROM visits \
+                 WHERE link_id = $1",
+            )
+            .bind::<Integer, _>(link_id)
+            .get_result::<VisitStatsRow>(c)
+        })
+        .await?;
+
+    Ok(VisitStats {
+        channels: ChannelCounts {
+            direct: row.direct,
+            qr: row.qr,
+        },
+        visitors: VisitorCounts {
+            human: row.human,
+            bot: row.bot,
+        },
+        unique_visitors: row.unique_visitors,
+    })
+}
+
+#[derive(QueryableByName, Serialize, Deserialize, Debug, PartialEq)]
+pub struct GroupCount {
+    #[sql_type = "Varchar"]
+    pub value: String,
+    #[sql_type = "BigInt"]
+    pub visits: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VisitBreakdown {
+    pub countries: Vec<GroupCount>,
+    pub regions: Vec<GroupCount>,
+    pub devices: Vec<GroupCount>,
+    pub operating_systems: Vec<GroupCount>,
+    pub browsers: Vec<GroupCount>,
+}
+
+// Human visits grouped by each column, most visits first
+pub async fn breakdown(link_id: i32, conn: &DbConn) -> QueryResult<VisitBreakdown> {
+    conn.run(move |c| {
+        let group = |column: &str| {
+            diesel::sql_query(format!(
+                "SELECT COALESCE({column}, 'unknown') AS value, COUNT(*) AS visits \
+                 FROM visits \
+                 WHERE link_id = $1 AND NOT is_bot \
+                 GROUP BY value \
+                 ORDER BY visits DESC, value",
+                column = column
+            ))
+            .bind::<Integer, _>(link_id)
+            .load::<GroupCount>(c)
+        };
+
+        Ok(VisitBreakdown {
+            countries: group("country")?,
+            regions: group("region")?,
+            devices: group("device")?,
+            operating_systems: group("os")?,
+            browsers: group("browser")?,
+        })
+    })
+    .await
+}
+
+// Referrers with the most visits between `from` and `to`, both days included
+pub async fn top_referrers(
+    link_id: i32,
+    from: Option<chrono::NaiveDate>,
+    to: Option<chrono::NaiveDate>,
+    limit: i64,
+    conn: &DbConn,
+) -> QueryResult<Vec<GroupCount>> {
+    conn.run(move |c| {
+        diesel::sql_query(
+            "SELECT referrer AS value, COUNT(*) AS visits \
+             FROM visits \
+             WHERE link_id = $1 AND referrer IS NOT NULL AND NOT is_bot \
+             AND ($2::date IS NULL OR created_at::date >= $2) \
+             AND ($3::date IS NULL OR created_at::date <= $3) \
+             GROUP BY referrer \
+             ORDER BY visits DESC, referrer \
+             LIMIT $4",
+        )
+        .bind::<Integer, _>(link_id)
+        .bind::<Nullable<Date>, _>(from)
+        .bind::<Nullable<Date>, _>(to)
+        .bind::<BigInt, _>(limit)
+        .load::<GroupCount>(c)
+    })
+    .await
+}
+
+// The registrable domain the visitor came from, so www.google.com and news.google.com
+// are both google.com
+pub struct Referrer(pub Option<String>);
+
+impl Referrer {
+    pub fn domain(referer: &str) -> Option<String> {
+        let url = Url::parse(referer).ok()?;
+        let host = url.host_str()?.trim_end_matches('.').to_lowercase();
+
+        // IP addresses and hosts without a registrable domain are kept as they are
+        let domain = match url.host()? {
+            Host::Domain(_) => psl::domain_str(&host).map_or(host.clone(), str::to_string),
+            Host::Ipv4(_) | Host::Ipv6(_) => host,
+        };
+
+        Some(domain).filter(|domain| domain.len() <= 255)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Referrer {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let domain = request
+            .headers()
+            .get_one("Referer")
+            .and_then(Referrer::domain);
+
+        Outcome::Success(Referrer(domain))
+    }
+}
+
+// Who may report the visitor's address, and in which header. Anyone can send X-Forwarded-For,
+// so without both set visits use the address of whoever connected.
+//   CLIENT_IP_HEADER  e.g. "X-Forwarded-For", as set by the load balancer in front of the app
+//   TRUSTED_PROXIES   comma separated addresses or CIDR ranges of those load balancers
+pub struct TrustedProxies {
+    pub header: Option<String>,
+    pub networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    pub fn from_env() -> Self {
+        TrustedProxies {
+            header: std::env::var("CLIENT_IP_HEADER")
+                .ok()
+                .filter(|header| !header.trim().is_empty()),
+            networks: std::env::var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|network| !network.is_empty())
+                .filter_map(|network| {
+                    let parsed = parse_network(network);
+
+                    if parsed.is_none() {
+                        log::warn!("Ignoring invalid trusted proxy '{}'", network);
+                    }
+
+                    parsed
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(network, prefix)| in_network(ip, *network, *prefix))
+    }
+
+    // Each proxy appends the address it was connected from, so the right-most address that
+    // isn't one of ours is the visitor. Anything left of it came from the visitor.
+    pub fn client_ip(&self, remote: Option<IpAddr>, forwarded: &[&str]) -> Option<IpAddr> {
+        let mut client = remote?;
+
+        if self.header.is_none() || !self.is_trusted(client) {
+            return Some(client);
+        }
+
+        for address in forwarded
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .rev()
+        {
+            match address.parse::<IpAddr>() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+
+        Some(client)
+    }
+}
+
+// An address or CIDR range, e.g. "10.0.0.1" or "10.0.0.0/8"
+fn parse_network(network: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = match network.split_once('/') {
+        Some((address, prefix)) => (address.parse::<IpAddr>().ok()?, prefix.parse::<u8>().ok()?),
+        None => {
+            let address = network.parse::<IpAddr>().ok()?;
+            let prefix = if address.is_ipv4() { 32 } else { 128 };
+
+            (address, prefix)
+        }
+    };
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+
+    Some((address, prefix)).filter(|_| prefix <= max_prefix)
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let remote = request.remote().map(|address| address.ip());
+        let ip = match request.rocket().state::<TrustedProxies>() {
+            Some(proxies) => {
+                let forwarded = match &proxies.header {
+                    Some(header) => request.headers().get(header).collect::<Vec<_>>(),
+                    None => vec![],
+                };
+
+                proxies.client_ip(remote, &forwarded)
+            }
+            None => remote,
+        };
+
+        Outcome::Success(ClientIp(ip))
+    }
+}
+
+pub mod schema {
+    table! {
+        visits (id) {
+            id -> Int8,
+            link_id -> Int4,
+            channel -> Varchar,
+            created_at -> Timestamp,
+            is_bot -> Bool,
+            visitor_hash -> Nullable<Varchar>,
+            country -> Nullable<Varchar>,
+            region -> Nullable<Varchar>,
+            device -> Nullable<Varchar>,
+            os -> Nullable<Varchar>,
+            browser -> Nullable<Varchar>,
+            referrer -> Nullable<Varchar>,
+        }
+    }
+
+    table! {
+        visitor_salts (day) {
+            day -> Date,
+            salt -> Varchar,
+        }
+    }
+}
@@ -0,0 +1,572 @@
+use std::time::Duration;
+
+use chrono::Timelike;
+use diesel::{self, pg::PgConnection, prelude::*};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::serde_json;
+use rocket::tokio;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use url::Url;
+
+use crate::{api::LinkResponse, http, link::Link, policy, DbConn, DbPool};
+
+use self::schema::{webhook_deliveries, webhooks};
+
+pub const EVENTS: &[&str] = &[
+    Event::Created.as_str(),
+    Event::Updated.as_str(),
+    Event::Deleted.as_str(),
+    Event::ClickThreshold.as_str(),
+];
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_BATCH_SIZE: i64 = 50;
+const MAX_ATTEMPTS: i32 = 8;
+// How long claimed deliveries are hidden from other workers while they're sent one by one,
+// long enough for every one in the batch to time out with a minute to spare
+const CLAIM_LEASE: i64 = DELIVERY_BATCH_SIZE * DELIVERY_TIMEOUT.as_secs() as i64 + 60;
+// Retries wait 30s, 1m, 2m, 4m... between attempts
+const RETRY_BASE_SECONDS: i64 = 30;
+
+const PENDING: &str = "pending";
+const DELIVERED: &str = "delivered";
+const FAILED: &str = "failed";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Created,
+    Updated,
+    Deleted,
+    ClickThreshold,
+}
+
+impl Event {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Event::Created => "link.created",
+            Event::Updated => "link.updated",
+            Event::Deleted => "link.deleted",
+            Event::ClickThreshold => "link.click_threshold",
+        }
+    }
+}
+
+#[derive(Queryable, Identifiable, AsChangeset, Serialize, Deserialize, Clone, Debug)]
+#[table_name = "webhooks"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    // Empty means every event
+    pub events: Vec<String>,
+    pub secret: String,
+    // Sends `link.click_threshold` once a link's visitors reach this many
+    pub click_threshold: Option<i32>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Queryable, Serialize, Deserialize, Clone, Debug)]
+pub struct Delivery {
+    pub id: i64,
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub next_attempt_at: chrono::NaiveDateTime,
+    pub created_at: chrono::NaiveDateTime,
+    pub delivered_at: Option<chrono::NaiveDateTime>,
+}
+
+impl Webhook {
+    pub async fn all(conn: &DbConn) -> QueryResult<Vec<Webhook>> {
+        conn.run(|c| webhooks::table.order(webhooks::id.asc()).load::<Self>(c))
+            .await
+    }
+
+    pub async fn find(id: i32, conn: &DbConn) -> Result<Webhook, String> {
+        conn.run(move |c| {
+            webhooks::table
+                .find(id)
+                .get_result::<Self>(c)
+                .map_err(|_| "Webhook not found".to_string())
+        })
+        .await
+    }
+
+    // A secret is generated when none is given
+    pub async fn insert(
+        url: String,
+        events: Vec<String>,
+        secret: Option<String>,
+        click_threshold: Option<i32>,
+        conn: &DbConn,
+    ) -> Result<Webhook, String> {
+        let secret = secret.unwrap_or_else(generate_secret);
+        let errors = validate(&url, &events, &secret, click_threshold);
+
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+
+        conn.run(move |c| {
+            diesel::insert_into(webhooks::table)
+                .values((
+                    webhooks::url.eq(url),
+                    webhooks::events.eq(events),
+                    webhooks::secret.eq(secret),
+                    webhooks::click_threshold.eq(click_threshold),
+                ))
+                .get_result::<Self>(c)
+                .map_err(|e| e.to_string())
+        })
+        .await
+    }
+
+    pub async fn update(
+        mut self,
+        changes: WebhookChanges,
+        conn: &DbConn,
+    ) -> Result<Webhook, String> {
+        if let Some(url) = changes.url {
+            self.url = url;
+        }
+
+        if let Some(events) = changes.events {
+            self.events = events;
+        }
+
+        if let Some(secret) = changes.secret {
+            self.secret = secret;
+        }
+
+        if let Some(click_threshold) = changes.click_threshold {
+            self.click_threshold = click_threshold;
+        }
+
+        let errors = validate(&self.url, &self.events, &self.secret, self.click_threshold);
+
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+
+        conn.run(move |c| self.save_changes(c).map_err(|e| e.to_string()))
+            .await
+    }
+
+    // Deliveries go with it
+    pub async fn delete(self, conn: &DbConn) -> bool {
+        conn.run(move |c| diesel::delete(&self).execute(c).is_ok())
+            .await
+    }
+
+    pub async fn delete_all(conn: &DbConn) -> QueryResult<usize> {
+        conn.run(|c| diesel::delete(webhooks::table).execute(c))
+            .await
+    }
+
+    pub async fn deliveries(&self, limit: i64, conn: &DbConn) -> QueryResult<Vec<Delivery>> {
+        let id = self.id;
+
+        conn.run(move |c| {
+            webhook_deliveries::table
+                .filter(webhook_deliveries::webhook_id.eq(id))
+                .order(webhook_deliveries::id.desc())
+                .limit(limit)
+                .load::<Delivery>(c)
+        })
+        .await
+    }
+
+    fn wants(&self, event: Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|wanted| wanted == event.as_str())
+    }
+}
+
+// Fields left as None aren't changed, `click_threshold: Some(None)` removes the threshold
+#[derive(Default)]
+pub struct WebhookChanges {
+    pub url: Option<String>,
+    pub events: Option<Vec<String>>,
+    pub secret: Option<String>,
+    pub click_threshold: Option<Option<i32>>,
+}
+
+fn validate(
+    url: &str,
+    events: &[String],
+    secret: &str,
+    click_threshold: Option<i32>,
+) -> Vec<String> {
+    let mut errors = vec![];
+
+    match Url::parse(url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            if !policy::allow_private_hosts() && policy::is_private_url(&url) {
+                errors.push("Webhook URL cannot point to a private or local address".to_string());
+            }
+        }
+        _ => errors.push("Webhook URL must be an http or https URL".to_string()),
+    }
+
+    for event in events {
+        if !EVENTS.contains(&event.as_str()) {
+            errors.push(format!(
+                "Unknown event '{}', expected one of {}",
+                event,
+                EVENTS.join(", ")
+            ));
+        }
+    }
+
+    if secret.len() < 16 {
+        errors.push("Secret must be at least 16 characters".to_string());
+    } else if secret.len() > 255 {
+        errors.push("Secret cannot be over 255 characters".to_string());
+    }
+
+    if matches!(click_threshold, Some(threshold) if threshold < 1) {
+        errors.push("Click threshold must be at least 1".to_string());
+    }
+
+    errors
+}
+
+fn generate_secret() -> String {
+    rand::thread_rng()
+        .gen::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Hex HMAC-SHA256 of the body, sent as `X-Kickshort-Signature: sha256=<signature>`
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key");
+
+    mac.update(body.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Queues the link for every webhook subscribed to the event, errors are only logged
+// so webhooks never fail the request that triggered them
+pub async fn enqueue(event: Event, link: &LinkResponse, conn: &DbConn) {
+    let payload = match serde_json::to_string(link) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("Failed to serialize webhook payload: {}", e);
+            return;
+        }
+    };
+
+    let result = Webhook::all(conn).await.map(|webhooks| {
+        webhooks
+            .into_iter()
+            .filter(|webhook| webhook.wants(event))
+            .map(|webhook| webhook.id)
+            .collect::<Vec<_>>()
+    });
+
+    match result {
+        Ok(webhook_ids) => insert_deliveries(webhook_ids, event, payload, conn).await,
+        Err(e) => log::error!("Failed to load webhooks: {}", e),
+    }
+}
+
+// Called with each link's visitors before and after a batch of visits is counted
+pub async fn enqueue_click_thresholds(crossed: Vec<(i32, i32, i32)>, conn: &DbConn) {
+    let webhooks = match Webhook::all(conn).await {
+        Ok(webhooks) => webhooks,
+        Err(e) => {
+            log::error!("Failed to load webhooks: {}", e);
+            return;
+        }
+    };
+
+    for (link_id, before, after) in crossed {
+        let webhook_ids: Vec<i32> = webhooks
+            .iter()
+            .filter(|webhook| webhook.wants(Event::ClickThreshold))
+            .filter(|webhook| {
+                webhook
+                    .click_threshold
+                    .map_or(false, |threshold| before < threshold && threshold <= after)
+            })
+            .map(|webhook| webhook.id)
+            .collect();
+
+        if webhook_ids.is_empty() {
+            continue;
+        }
+
+        let link = match Link::find(link_id, conn).await {
+            Ok(link) => link,
+            Err(_) => continue,
+        };
+        let link = match LinkResponse::build(link, conn).await {
+            Ok(link) => link,
+            Err(e) => {
+                log::error!("Failed to load link {} for webhooks: {}", link_id, e);
+                continue;
+            }
+        };
+
+        match serde_json::to_string(&link) {
+            Ok(payload) => {
+                insert_deliveries(webhook_ids, Event::ClickThreshold, payload, conn).await
+            }
+            Err(e) => log::error!("Failed to serialize webhook payload: {}", e),
+        }
+    }
+}
+
+async fn insert_deliveries(webhook_ids: Vec<i32>, event: Event, payload: String, conn: &DbConn) {
+    if webhook_ids.is_empty() {
+        return;
+    }
+
+    let result = conn
+        .run(move |c| {
+            let rows: Vec<_> = webhook_ids
+                .into_iter()
+                .map(|webhook_id| {
+                    (
+                        webhook_deliveries::webhook_id.eq(webhook_id),
+                        webhook_deliveries::event.eq(event.as_str()),
+                        webhook_deliveries::payload.eq(&payload),
+                    )
+                })
+                .collect();
+
+            diesel::insert_into(webhook_deliveries::table)
+                .values(&rows)
+                .execute(c)
+        })
+        .await;
+
+    if let Err(e) = result {
+        log::error!("Failed to queue {} webhooks: {}", event.as_str(), e);
+    }
+}
+
+// Claims due deliveries so other instances skip them, then sends each one.
+// Returns how many were claimed. No connection is held while sending.
+pub async fn deliver_due(client: &reqwest::Client, pool: &DbPool) -> Result<usize, String> {
+    let connect = move || async move {
+        DbConn::from_pool(pool)
+            .await
+            .ok_or_else(|| "Database connection unavailable".to_string())
+    };
+
+    let claimed = connect()
+        .await?
+        .run(|c| c.transaction(|| claim_due(c)))
+        .await
+        .map_err(|e| e.to_string())?;
+    let count = claimed.len();
+
+    for (delivery, webhook) in claimed {
+        let id = delivery.id;
+        let outcome = send(client, &delivery, &webhook).await;
+        let conn = match connect().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("Failed to record webhook delivery {}: {}", id, e);
+                continue;
+            }
+        };
+
+        match record_attempt(delivery, outcome, &conn).await {
+            Ok(0) => log::warn!(
+                "Webhook delivery {} was claimed again before it was sent",
+                id
+            ),
+            Ok(_) => {}
+            Err(e) => log::error!("Failed to record webhook delivery: {}", e),
+        }
+    }
+
+    Ok(count)
+}
+
+// A claimed delivery's next_attempt_at is its lease, and doubles as the claim:
+// a worker only records an attempt while the delivery still has the lease it set.
+fn claim_due(c: &PgConnection) -> QueryResult<Vec<(Delivery, Webhook)>> {
+    let now = chrono::Utc::now().naive_utc();
+
+    let due = webhook_deliveries::table
+        .filter(webhook_deliveries::status.eq(PENDING))
+        .filter(webhook_deliveries::next_attempt_at.le(now))
+        .order(webhook_deliveries::id.asc())
+        .limit(DELIVERY_BATCH_SIZE)
+        .for_update()
+        .skip_locked()
+        .load::<Delivery>(c)?;
+
+    let ids: Vec<i64> = due.iter().map(|delivery| delivery.id).collect();
+
+    // Postgres keeps microseconds, so the lease has to be comparable once it's stored
+    let leased_until = now + chrono::Duration::seconds(CLAIM_LEASE);
+    let leased_until = leased_until
+        .with_nanosecond(leased_until.nanosecond() / 1_000 * 1_000)
+        .unwrap_or(leased_until);
+
+    diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)))
+        .set(webhook_deliveries::next_attempt_at.eq(leased_until))
+        .execute(c)?;
+
+    let webhook_ids: Vec<i32> = due.iter().map(|delivery| delivery.webhook_id).collect();
+    let webhooks = webhooks::table
+        .filter(webhooks::id.eq_any(webhook_ids))
+        .load::<Webhook>(c)?;
+
+    Ok(due
+        .into_iter()
+        .map(|delivery| Delivery {
+            next_attempt_at: leased_until,
+            ..delivery
+        })
+        .filter_map(|delivery| {
+            webhooks
+                .iter()
+                .find(|webhook| webhook.id == delivery.webhook_id)
+                .cloned()
+                .map(|webhook| (delivery, webhook))
+        })
+        .collect())
+}
+
+// The response status, if there was one, and an error for anything but a 2xx
+async fn send(
+    client: &reqwest::Client,
+    delivery: &Delivery,
+    webhook: &Webhook,
+) -> (Option<i32>, Option<String>) {
+    let response = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Kickshort-Event", &delivery.event)
+        .header("X-Kickshort-Delivery", delivery.id.to_string())
+        .header(
+            "X-Kickshort-Signature",
+            format!("sha256={}", sign(&webhook.secret, &delivery.payload)),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => {
+            (Some(response.status().as_u16() as i32), None)
+        }
+        Ok(response) => (
+            Some(response.status().as_u16() as i32),
+            Some(format!("Subscriber responded with {}", response.status())),
+        ),
+        Err(e) => (None, Some(http::describe(e))),
+    }
+}
+
+async fn record_attempt(
+    delivery: Delivery,
+    (response_status, error): (Option<i32>, Option<String>),
+    conn: &DbConn,
+) -> QueryResult<usize> {
+    let now = chrono::Utc::now().naive_utc();
+    let attempts = delivery.attempts + 1;
+    let (status, delivered_at, next_attempt_at) = match &error {
+        None => (DELIVERED, Some(now), now),
+        Some(_) if attempts >= MAX_ATTEMPTS => (FAILED, None, now),
+        Some(_) => {
+            let delay = RETRY_BASE_SECONDS * 2_i64.pow(attempts as u32 - 1);
+            (PENDING, None, now + chrono::Duration::seconds(delay))
+        }
+    };
+
+    conn.run(move |c| {
+        diesel::update(
+            webhook_deliveries::table
+                .find(delivery.id)
+                .filter(webhook_deliveries::status.eq(PENDING))
+                .filter(webhook_deliveries::next_attempt_at.eq(delivery.next_attempt_at)),
+        )
+        .set((
+            webhook_deliveries::status.eq(status),
+            webhook_deliveries::attempts.eq(attempts),
+            webhook_deliveries::response_status.eq(response_status),
+            webhook_deliveries::last_error.eq(error),
+            webhook_deliveries::next_attempt_at.eq(next_attempt_at),
+            webhook_deliveries::delivered_at.eq(delivered_at),
+        ))
+        .execute(c)
+    })
+    .await
+}
+
+// WEBHOOK_POLL_INTERVAL is in seconds
+pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Webhook Deliveries", |rocket| {
+        Box::pin(async move {
+            let pool = match DbConn::pool(rocket) {
+                Some(pool) => pool.clone(),
+                None => return,
+            };
+            let interval = std::env::var("WEBHOOK_POLL_INTERVAL")
+                .ok()
+                .and_then(|seconds| seconds.parse::<u64>().ok())
+                .map_or(DEFAULT_POLL_INTERVAL, Duration::from_secs);
+            let client = http::fetch_client(DELIVERY_TIMEOUT, policy::allow_private_hosts());
+
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+
+                    if let Err(e) = deliver_due(&client, &pool).await {
+                        log::error!("Failed to deliver webhooks: {}", e);
+                    }
+                }
+            });
+        })
+    })
+}
+
+pub mod schema {
+    table! {
+        webhooks (id) {
+            id -> Int4,
+            url -> Varchar,
+            events -> Array<Text>,
+            secret -> Varchar,
+            click_threshold -> Nullable<Int4>,
+            created_at -> Timestamp,
+        }
+    }
+
+    table! {
+        webhook_deliveries (id) {
+            id -> Int8,
+            webhook_id -> Int4,
+            event -> Varchar,
+            payload -> Text,
+            status -> Varchar,
+            attempts -> Int4,
+            response_status -> Nullable<Int4>,
+            last_error -> Nullable<Text>,
+            next_attempt_at -> Timestamp,
+            created_at -> Timestamp,
+            delivered_at -> Nullable<Timestamp>,
+        }
+    }
+}
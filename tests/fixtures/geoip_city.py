@@ -0,0 +1,84 @@
+# Writes geoip_city.mmdb, a GeoIP2 City style database with a single network for tests:
+#   81.2.69.0/24 -> GB, England
+# Follows the MaxMind DB format, https://maxmind.github.io/MaxMind-DB/
+import os
+import struct
+import time
+
+NETWORK = (81, 2, 69, 0)
+PREFIX = 24
+RECORD = {
+    "country": {"iso_code": "GB", "names": {"en": "United Kingdom"}},
+    "subdivisions": [{"iso_code": "ENG", "names": {"en": "England"}}],
+}
+
+
+def control(kind, size):
+    if size < 29:
+        size_bits, extra = size, b""
+    elif size < 285:
+        size_bits, extra = 29, bytes([size - 29])
+    else:
+        size_bits, extra = 30, struct.pack(">H", size - 285)
+
+    if kind <= 7:
+        return bytes([(kind << 5) | size_bits]) + extra
+
+    return bytes([size_bits, kind - 7]) + extra
+
+
+def encode(value, kind=None):
+    if isinstance(value, str):
+        data = value.encode()
+        return control(2, len(data)) + data
+    if isinstance(value, dict):
+        return control(7, len(value)) + b"".join(
+            encode(key) + encode(item) for key, item in value.items()
+        )
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(item) for item in value)
+    if isinstance(value, int):
+        data = value.to_bytes((value.bit_length() + 7) // 8, "big")
+        return control(kind, len(data)) + data
+    raise TypeError(value)
+
+
+class Uint:
+    def __init__(self, kind, value):
+        self.kind, self.value = kind, value
+
+
+def encode_metadata(metadata):
+    parts = []
+    for key, value in metadata.items():
+        parts.append(encode(key))
+        parts.append(encode(value.value, value.kind) if isinstance(value, Uint) else encode(value))
+    return control(7, len(metadata)) + b"".join(parts)
+
+
+bits = [(NETWORK[i // 8] >> (7 - i % 8)) & 1 for i in range(PREFIX)]
+node_count = PREFIX
+empty = node_count
+data_record = node_count + 16
+
+tree = b""
+for depth, bit in enumerate(bits):
+    next_record = depth + 1 if depth + 1 < PREFIX else data_record
+    left, right = (empty, next_record) if bit else (next_record, empty)
+    tree += left.to_bytes(3, "big") + right.to_bytes(3, "big")
+
+metadata = encode_metadata({
+    "binary_format_major_version": Uint(5, 2),
+    "binary_format_minor_version": Uint(5, 0),
+    "build_epoch": Uint(9, int(time.time())),
+    "database_type": "GeoIP2-City",
+    "description": {"en": "kickshort test fixture"},
+    "ip_version": Uint(5, 4),
+    "languages": ["en"],
+    "node_count": Uint(6, node_count),
+    "record_size": Uint(5, 24),
+})
+
+path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "geoip_city.mmdb")
+with open(path, "wb") as database:
+    database.write(tree + b"\x00" * 16 + encode(RECORD) + b"\xab\xcd\xefMaxMind.com" + metadata)